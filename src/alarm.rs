@@ -0,0 +1,282 @@
+//! CO2 alarms on top of the sensor data updates.
+//!
+//! An `Alarm` classifies the CO2 concentration into the `AirQuality` levels of the traffic light
+//! on the device. To keep the level from flapping around a threshold, it only improves once the
+//! concentration fell below the threshold by the hysteresis, and a new level has to hold for the
+//! minimum dwell time before it is reported as an `AlarmEvent`. As readings are only delivered when
+//! a value changes, the monitoring thread also checks the dwell time while no readings arrive.
+
+use crate::DeviceData;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::time::Duration;
+
+pub(crate) type AlarmCallback = Box<dyn FnMut(&AlarmEvent) + Send>;
+
+/// The air quality levels, ordered from good to poor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AirQuality {
+    /// The CO2 concentration is below the fair threshold, the green light on the device.
+    Good,
+    /// The CO2 concentration is between the fair and poor thresholds, the yellow light on the device.
+    Fair,
+    /// The CO2 concentration is above the poor threshold, the red light on the device.
+    Poor,
+}
+
+impl fmt::Display for AirQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AirQuality::Good => write!(f, "good"),
+            AirQuality::Fair => write!(f, "fair"),
+            AirQuality::Poor => write!(f, "poor"),
+        }
+    }
+}
+
+/// The thresholds of an `Alarm`.
+///
+/// # Fields
+/// - `fair`: The CO2 concentration in ppm from which on the air quality is fair.
+/// - `poor`: The CO2 concentration in ppm from which on the air quality is poor.
+/// - `hysteresis`: How far in ppm the concentration has to fall below a threshold before the level improves.
+/// - `min_dwell`: How long a new level has to hold before it is reported, measured by the time of the
+///   readings, or by the current time while no new reading arrives from a live device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlarmConfig {
+    pub fair: u16,
+    pub poor: u16,
+    pub hysteresis: u16,
+    pub min_dwell: Duration,
+}
+
+impl Default for AlarmConfig {
+    /// Returns the thresholds of the traffic light on the device, 800 and 1200 ppm, with a hysteresis
+    /// of 50 ppm and no minimum dwell time.
+    fn default() -> Self {
+        AlarmConfig {
+            fair: 800,
+            poor: 1200,
+            hysteresis: 50,
+            min_dwell: Duration::ZERO,
+        }
+    }
+}
+
+impl AlarmConfig {
+    /// Returns the level of a CO2 concentration without hysteresis.
+    pub fn level(&self, co2: u16) -> AirQuality {
+        if co2 >= self.poor {
+            AirQuality::Poor
+        } else if co2 >= self.fair {
+            AirQuality::Fair
+        } else {
+            AirQuality::Good
+        }
+    }
+}
+
+/// A change of the air quality level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AlarmEvent {
+    /// The air quality got worse.
+    AlarmRaised { level: AirQuality, previous: AirQuality, data: DeviceData },
+    /// The air quality improved.
+    AlarmCleared { level: AirQuality, previous: AirQuality, data: DeviceData },
+}
+
+impl AlarmEvent {
+    /// Returns the new air quality level.
+    pub fn level(&self) -> AirQuality {
+        match self {
+            AlarmEvent::AlarmRaised { level, .. } | AlarmEvent::AlarmCleared { level, .. } => *level,
+        }
+    }
+
+    /// Returns the reading which triggered the change.
+    pub fn data(&self) -> &DeviceData {
+        match self {
+            AlarmEvent::AlarmRaised { data, .. } | AlarmEvent::AlarmCleared { data, .. } => data,
+        }
+    }
+}
+
+/// Tracks the air quality level of a series of readings.
+///
+/// The level starts at `AirQuality::Good`, so a first reading above a threshold raises an alarm.
+///
+/// # Fields
+/// - `config`: The thresholds of the alarm.
+/// - `level`: The last reported level.
+/// - `candidate`: The level the readings currently point to if it differs from `level`, and the
+///   time the readings left `level` in its direction.
+/// - `last`: The last reading, which is reported when its level held for the minimum dwell time
+///   without further readings.
+#[derive(Debug, Clone)]
+pub struct Alarm {
+    config: AlarmConfig,
+    level: AirQuality,
+    candidate: Option<(AirQuality, DateTime<Utc>)>,
+    last: Option<DeviceData>,
+}
+
+impl Alarm {
+    /// Creates an alarm with the given thresholds.
+    pub fn new(config: AlarmConfig) -> Self {
+        Alarm { config, level: AirQuality::Good, candidate: None, last: None }
+    }
+
+    /// Returns the thresholds of the alarm.
+    pub fn config(&self) -> &AlarmConfig {
+        &self.config
+    }
+
+    /// Returns the last reported air quality level.
+    pub fn level(&self) -> AirQuality {
+        self.level
+    }
+
+    /// Adds a reading.
+    ///
+    /// # Returns
+    /// The event if the level changed and the new level held for the minimum dwell time.
+    pub fn update(&mut self, data: &DeviceData) -> Option<AlarmEvent> {
+        self.last = Some(*data);
+        let target = self.target(data.co2());
+        if target == self.level {
+            self.candidate = None;
+            return None;
+        }
+        // The dwell time counts from the first reading leaving the level in the same direction, so
+        // readings alternating between fair and poor still raise an alarm after a good level.
+        let since = match self.candidate {
+            Some((candidate, since)) if (candidate > self.level) == (target > self.level) => since,
+            _ => data.time(),
+        };
+        self.candidate = Some((target, since));
+        if (data.time() - since).to_std().unwrap_or_default() < self.config.min_dwell {
+            return None;
+        }
+        Some(self.report(target, *data))
+    }
+
+    /// Checks the minimum dwell time at `now` while no new reading arrived, assuming the
+    /// concentration of the last reading still holds.
+    ///
+    /// # Returns
+    /// The event with the last reading if its level now held for the minimum dwell time.
+    pub fn check(&mut self, now: DateTime<Utc>) -> Option<AlarmEvent> {
+        let (target, since) = self.candidate?;
+        let data = self.last?;
+        if (now - since).to_std().unwrap_or_default() < self.config.min_dwell {
+            return None;
+        }
+        Some(self.report(target, data))
+    }
+
+    /// Changes the level to `target` and returns the event reporting it.
+    fn report(&mut self, target: AirQuality, data: DeviceData) -> AlarmEvent {
+        let previous = self.level;
+        self.level = target;
+        self.candidate = None;
+        if target > previous {
+            AlarmEvent::AlarmRaised { level: target, previous, data }
+        } else {
+            AlarmEvent::AlarmCleared { level: target, previous, data }
+        }
+    }
+
+    /// Returns the level a concentration points to, applying the hysteresis when the level improves.
+    fn target(&self, co2: u16) -> AirQuality {
+        let level = self.config.level(co2);
+        if level >= self.level {
+            return level;
+        }
+        self.config.level(co2.saturating_add(self.config.hysteresis)).min(self.level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn reading(seconds: i64, co2: u16) -> DeviceData {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap() + chrono::Duration::seconds(seconds);
+        DeviceData::new(time, co2, 21.0, Some(45.0))
+    }
+
+    /// Feeds the readings into `alarm` and returns the levels of the reported events.
+    fn levels(alarm: &mut Alarm, readings: &[(i64, u16)]) -> Vec<Option<AirQuality>> {
+        readings.iter().map(|&(seconds, co2)| alarm.update(&reading(seconds, co2)).map(|event| event.level())).collect()
+    }
+
+    #[test]
+    fn classifies_the_concentration() {
+        let config = AlarmConfig::default();
+        assert_eq!(config.level(799), AirQuality::Good);
+        assert_eq!(config.level(800), AirQuality::Fair);
+        assert_eq!(config.level(1199), AirQuality::Fair);
+        assert_eq!(config.level(1200), AirQuality::Poor);
+    }
+
+    #[test]
+    fn improves_only_below_the_hysteresis() {
+        let mut alarm = Alarm::new(AlarmConfig::default());
+
+        let events = levels(&mut alarm, &[(0, 1300), (1, 1180), (2, 1150), (3, 1149), (4, 760), (5, 749)]);
+
+        assert_eq!(events, [Some(AirQuality::Poor), None, None, Some(AirQuality::Fair), None, Some(AirQuality::Good)]);
+    }
+
+    #[test]
+    fn raises_and_clears_events() {
+        let mut alarm = Alarm::new(AlarmConfig::default());
+
+        let raised = alarm.update(&reading(0, 900)).unwrap();
+        let cleared = alarm.update(&reading(1, 700)).unwrap();
+
+        assert_eq!(raised, AlarmEvent::AlarmRaised { level: AirQuality::Fair, previous: AirQuality::Good, data: reading(0, 900) });
+        assert_eq!(cleared, AlarmEvent::AlarmCleared { level: AirQuality::Good, previous: AirQuality::Fair, data: reading(1, 700) });
+        assert_eq!(alarm.level(), AirQuality::Good);
+    }
+
+    #[test]
+    fn reports_a_level_which_holds_without_further_readings() {
+        let config = AlarmConfig { min_dwell: Duration::from_secs(60), ..AlarmConfig::default() };
+        let mut alarm = Alarm::new(config);
+        let start = reading(0, 1300);
+
+        assert_eq!(alarm.check(start.time()), None);
+        assert_eq!(alarm.update(&start), None);
+        assert_eq!(alarm.check(start.time() + chrono::Duration::seconds(59)), None);
+        let event = alarm.check(start.time() + chrono::Duration::seconds(60));
+
+        assert_eq!(event, Some(AlarmEvent::AlarmRaised { level: AirQuality::Poor, previous: AirQuality::Good, data: start }));
+        assert_eq!(alarm.check(start.time() + chrono::Duration::seconds(120)), None);
+    }
+
+    #[test]
+    fn reports_a_level_after_the_minimum_dwell_time() {
+        let config = AlarmConfig { min_dwell: Duration::from_secs(60), ..AlarmConfig::default() };
+        let mut alarm = Alarm::new(config);
+
+        // A short spike is not reported, the dwell time starts over once the level is left.
+        let events = levels(&mut alarm, &[(0, 900), (30, 700), (40, 900), (90, 1300), (100, 900)]);
+
+        assert_eq!(events, [None, None, None, None, Some(AirQuality::Fair)]);
+    }
+
+    #[test]
+    fn dwell_time_continues_between_worse_levels() {
+        let config = AlarmConfig { min_dwell: Duration::from_secs(60), ..AlarmConfig::default() };
+        let mut alarm = Alarm::new(config);
+
+        let events = levels(&mut alarm, &[(0, 1300), (30, 900), (60, 1300)]);
+
+        assert_eq!(events, [None, None, Some(AirQuality::Poor)]);
+    }
+}
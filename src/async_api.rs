@@ -0,0 +1,262 @@
+//! An async interface for tokio based applications, enabled by the `tokio` feature.
+//!
+//! `AsyncAirControl` reads the device on tokio's blocking thread pool with the same decoder as the
+//! monitoring thread of `AirControl`, and exposes the readings as a `Stream`. All streams of an
+//! instance are fed by a single reader, so every stream receives every reading.
+
+use crate::error::{Error, Result};
+use crate::measurement::Aggregator;
+use crate::monitor::Monitor;
+use crate::transport::Transport;
+use crate::{lock, AirControl, DeviceData};
+use futures_core::Stream;
+use hidapi::HidError;
+use std::panic;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// The number of readings buffered by a `DeviceDataStream` before the reader waits for the consumer.
+const STREAM_BUFFER: usize = 16;
+
+/// An async interface for the AirControl coach and mini devices.
+///
+/// All reads are executed on tokio's blocking thread pool, so a tokio runtime must be running.
+pub struct AsyncAirControl {
+    monitor: Arc<Monitor>,
+    streams: Arc<Mutex<Streams>>,
+}
+
+/// The open streams of an `AsyncAirControl`.
+///
+/// # Fields
+/// - `senders`: The channels of the streams which receive the readings.
+/// - `reading`: Whether a reader is running on the blocking thread pool.
+#[derive(Default)]
+struct Streams {
+    senders: Vec<mpsc::Sender<Result<DeviceData>>>,
+    reading: bool,
+}
+
+impl AsyncAirControl {
+    /// Initializes a new instance for the first attached device.
+    ///
+    /// # Errors
+    /// Returns the same errors as `AirControl::new`.
+    pub fn new() -> Result<Self> {
+        AirControl::new().map(Self::from)
+    }
+
+    /// Initializes a new instance on top of the given transport.
+    ///
+    /// # Errors
+    /// Returns the same errors as `AirControl::with_transport`.
+    pub fn with_transport<T: Transport + 'static>(device: T) -> Result<Self> {
+        AirControl::with_transport(device).map(Self::from)
+    }
+
+    /// Reads a single set of sensor readings from the device.
+    ///
+    /// Waits until all measurements required by the monitor's aggregator were received. The device
+    /// is read directly, so frames read here are missed by open streams; use the next item of a
+    /// stream instead while one is open.
+    ///
+    /// # Errors
+    /// Returns `Error::ReadTimeout` if the device did not send a report within the timeout,
+    /// `Error::MalformedFrame` if it sent a report which failed the validation and
+    /// `Error::Disconnected` if the device cannot be read.
+    pub async fn read_once(&self) -> Result<DeviceData> {
+        let monitor = self.monitor.clone();
+        task::spawn_blocking(move || {
+            let mut aggregator = lock(&monitor.aggregator).cleared();
+            loop {
+                if let Some(data) = read(&monitor, &mut aggregator)? {
+                    return Ok(data);
+                }
+            }
+        }).await
+            .unwrap_or_else(|error| panic::resume_unwind(error.into_panic()))
+    }
+
+    /// Returns a stream of the sensor readings of the device.
+    ///
+    /// Reports which fail the validation are yielded as `Error::MalformedFrame` and the stream
+    /// continues. The stream ends after yielding `Error::Disconnected` when the device cannot be
+    /// read anymore.
+    ///
+    /// All streams are fed by a single reader, which starts with the first stream and stops reading
+    /// the device when the last stream is dropped. Every stream receives every reading produced
+    /// while it is open, and the reader waits for the slowest stream once its buffer is full.
+    pub fn stream(&self) -> DeviceDataStream {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let mut streams = lock(&self.streams);
+        streams.senders.push(sender);
+        if !streams.reading {
+            streams.reading = true;
+            let monitor = self.monitor.clone();
+            let streams = self.streams.clone();
+            task::spawn_blocking(move || broadcast(&monitor, &streams));
+        }
+        DeviceDataStream { receiver }
+    }
+}
+
+/// Reads the device and sends every reading to all open streams, until the last stream is dropped
+/// or the device is disconnected.
+fn broadcast(monitor: &Monitor, streams: &Mutex<Streams>) {
+    let mut aggregator = lock(&monitor.aggregator).cleared();
+    loop {
+        // The device is read frame by frame, so dropped streams are noticed after the next frame
+        // instead of the next complete reading.
+        {
+            let mut streams = lock(streams);
+            streams.senders.retain(|sender| !sender.is_closed());
+            if streams.senders.is_empty() {
+                streams.reading = false;
+                return;
+            }
+        }
+        let result = read(monitor, &mut aggregator);
+        let senders = lock(streams).senders.clone();
+        match result {
+            Ok(Some(data)) => {
+                for sender in &senders {
+                    let _ = sender.blocking_send(Ok(data));
+                }
+            }
+            // A device which has nothing to report is still alive, keep waiting for it.
+            Ok(None) | Err(Error::ReadTimeout) => {}
+            Err(Error::MalformedFrame(error)) => {
+                for sender in &senders {
+                    let _ = sender.blocking_send(Err(Error::MalformedFrame(error)));
+                }
+            }
+            Err(error) => {
+                // The streams end with the error, a later stream starts a new reader.
+                let senders = {
+                    let mut streams = lock(streams);
+                    streams.reading = false;
+                    std::mem::take(&mut streams.senders)
+                };
+                // The HID error cannot be cloned, so the other streams receive its message.
+                let message = match &error {
+                    Error::Disconnected(source) => source.to_string(),
+                    error => error.to_string(),
+                };
+                let mut error = Some(error);
+                for sender in &senders {
+                    let error = error.take().unwrap_or_else(|| Error::Disconnected(HidError::HidApiError { message: message.clone() }));
+                    let _ = sender.blocking_send(Err(error));
+                }
+                return;
+            }
+        }
+    }
+}
+
+impl From<AirControl> for AsyncAirControl {
+    /// Converts an `AirControl` into its async counterpart, stopping its monitoring thread.
+    fn from(mut air_control: AirControl) -> Self {
+        air_control.stop_monitoring();
+        AsyncAirControl { monitor: air_control.monitor.clone(), streams: Arc::default() }
+    }
+}
+
+/// A stream of the sensor readings of a device, created by `AsyncAirControl::stream`.
+pub struct DeviceDataStream {
+    receiver: mpsc::Receiver<Result<DeviceData>>,
+}
+
+impl Stream for DeviceDataStream {
+    type Item = Result<DeviceData>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+fn read(monitor: &Monitor, aggregator: &mut Aggregator) -> Result<Option<DeviceData>> {
+    let device = lock(&monitor.device);
+    monitor.read_data(device.as_ref(), aggregator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use crate::item::ItemCode;
+    use crate::transport::ScriptedDevice;
+    use hidapi::HidResult;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    /// A device which keeps sending the CO2 concentration without ever completing a reading.
+    #[derive(Clone, Default)]
+    struct Co2Device {
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl Transport for Co2Device {
+        fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> HidResult<usize> {
+            thread::sleep(Duration::from_millis(1));
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            buf[..8].copy_from_slice(&Frame { item: ItemCode::Co2.code(), value: 650 }.to_bytes());
+            Ok(8)
+        }
+
+        fn send_feature_report(&self, _data: &[u8]) -> HidResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_stream_receives_every_reading() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _guard = runtime.enter();
+        let device = ScriptedDevice::new();
+        for co2 in 600..610 {
+            device.push_reading(ItemCode::Co2.code(), co2);
+            device.push_reading(ItemCode::Temperature.code(), 4700);
+            device.push_reading(ItemCode::Humidity.code(), 5000);
+        }
+        let air_control = AsyncAirControl::with_transport(device).unwrap();
+
+        // Holding the device keeps the reader from reading before both streams are open.
+        let device = lock(&air_control.monitor.device);
+        let mut streams = [air_control.stream(), air_control.stream()];
+        drop(device);
+
+        for stream in &mut streams {
+            let items = runtime.block_on(async {
+                let mut items = Vec::new();
+                while let Some(item) = stream.receiver.recv().await {
+                    items.push(item);
+                }
+                items
+            });
+            let co2: Vec<u16> = items.iter().filter_map(|item| item.as_ref().ok()).map(DeviceData::co2).collect();
+            assert_eq!(co2, (600..610).collect::<Vec<_>>());
+            assert!(matches!(items.last(), Some(Err(Error::Disconnected(_)))), "{:?}", items.last());
+        }
+    }
+
+    #[test]
+    fn dropping_the_stream_stops_reading_between_frames() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _guard = runtime.enter();
+        let device = Co2Device::default();
+        let air_control = AsyncAirControl::with_transport(device.clone()).unwrap();
+
+        let stream = air_control.stream();
+        thread::sleep(Duration::from_millis(20));
+        drop(stream);
+        thread::sleep(Duration::from_millis(20));
+        let reads = device.reads.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(device.reads.load(Ordering::SeqCst), reads);
+    }
+}
@@ -0,0 +1,193 @@
+//! Configuration of an `AirControl` before the device is opened.
+//!
+//! `AirControlBuilder` selects the device and sets the polling interval, read timeout,
+//! reconnect policy and required measurements. All `AirControl` constructors use it internally.
+
+use crate::device::{self, DeviceInfo};
+use crate::error::{Error, Result};
+use crate::frame::{FrameDecoder, DEFAULT_KEY};
+use crate::measurement::{Aggregator, MeasurementKind};
+use crate::monitor::{Connector, Monitor};
+use crate::reconnect::ReconnectPolicy;
+use crate::replay::{NoDevice, ReplayDevice};
+use crate::transport::Transport;
+use crate::AirControl;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The default minimum interval between two sensor data updates.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The default time to wait for a single report of the device.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Selects which attached device is opened.
+#[derive(Debug, Clone)]
+enum Selector {
+    First,
+    SerialNumber(String),
+    Path(String),
+}
+
+/// Builds an `AirControl` with custom settings, created by `AirControl::builder`.
+///
+/// # Fields
+/// - `selector`: Selects which attached device is opened.
+/// - `poll_interval`: The minimum interval between two sensor data updates.
+/// - `read_timeout`: The time to wait for a single report of the device.
+/// - `reconnect_policy`: Controls the reconnection after a disconnection.
+/// - `required`: The kinds of measurements waited for before sensor data is emitted.
+#[derive(Debug, Clone)]
+pub struct AirControlBuilder {
+    selector: Selector,
+    poll_interval: Duration,
+    read_timeout: Duration,
+    reconnect_policy: ReconnectPolicy,
+    required: Vec<MeasurementKind>,
+}
+
+impl Default for AirControlBuilder {
+    fn default() -> Self {
+        AirControlBuilder {
+            selector: Selector::First,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            reconnect_policy: ReconnectPolicy::default(),
+            required: Aggregator::default().required().to_vec(),
+        }
+    }
+}
+
+impl AirControlBuilder {
+    /// Opens the device with the given serial number instead of the first attached device.
+    pub fn serial_number(mut self, serial_number: &str) -> Self {
+        self.selector = Selector::SerialNumber(serial_number.to_string());
+        self
+    }
+
+    /// Opens the device with the given HID path instead of the first attached device.
+    pub fn path(mut self, path: &str) -> Self {
+        self.selector = Selector::Path(path.to_string());
+        self
+    }
+
+    /// Sets the minimum interval between two sensor data updates.
+    ///
+    /// The device is read continuously, so an update always carries the latest values. Updates
+    /// in between are coalesced. Single measurements and raw values are not affected.
+    /// Sensor data is only delivered when it changed, so the interval is a minimum gap between two
+    /// changes and not a sampling period: while the readings are stable, no update is delivered.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Sets the time to wait for a single report of the device.
+    ///
+    /// Stopping the monitoring may take up to this timeout. Timeouts below one millisecond are
+    /// raised to one millisecond, as a zero timeout would poll the device without ever waiting.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Sets the policy used to re-open the device after a disconnection.
+    pub fn reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// Sets the kinds of measurements the device reports, see `AirControl::set_required_measurements`.
+    pub fn required_measurements(mut self, required: &[MeasurementKind]) -> Self {
+        self.required = required.to_vec();
+        self
+    }
+
+    /// Opens the selected device.
+    ///
+    /// After a disconnection, the device is re-opened at its original path if a device with the
+    /// same serial number is attached there. Otherwise it is re-opened by its serial number if it
+    /// has one, e.g. after it was plugged into another port.
+    ///
+    /// # Errors
+    /// Returns `Error::Api` if the HID API instance cannot be created, `Error::DeviceNotFound` if
+    /// no attached device matches the selection, `Error::AmbiguousDevice` if several attached
+    /// devices have the selected serial number, `Error::Open` if the device cannot be opened and
+    /// `Error::FeatureReport` if the initial feature report cannot be sent.
+    pub fn open(self) -> Result<AirControl> {
+        let (device, info) = match &self.selector {
+            Selector::First => device::open_first()?,
+            Selector::SerialNumber(serial_number) => device::open(|info| info.serial_number.as_ref() == Some(serial_number))?,
+            Selector::Path(path) => device::open(|info| &info.path == path)?,
+        };
+        let reopen = info.clone();
+        let connector: Connector = Box::new(move || {
+            let (device, _) = match device::open(|info| info.path == reopen.path && info.serial_number == reopen.serial_number) {
+                Err(Error::DeviceNotFound) if reopen.serial_number.is_some() => {
+                    device::open(|info| info.serial_number == reopen.serial_number)?
+                }
+                result => result?,
+            };
+            Ok(Box::new(device) as Box<dyn Transport>)
+        });
+        self.build(Box::new(device), Some(connector), Some(info))
+    }
+
+    /// Builds the `AirControl` on top of the given transport, see `AirControl::with_transport`.
+    ///
+    /// # Errors
+    /// Returns `Error::FeatureReport` if the initial feature report cannot be sent.
+    pub fn with_transport<T: Transport + 'static>(self, device: T) -> Result<AirControl> {
+        self.build(Box::new(device), None, None)
+    }
+
+    /// Builds the `AirControl` on top of transports created by `connect`, see `AirControl::with_connector`.
+    ///
+    /// # Errors
+    /// Returns the error of `connect` if the initial transport cannot be created and
+    /// `Error::FeatureReport` if the initial feature report cannot be sent.
+    pub fn with_connector<T, F>(self, connect: F) -> Result<AirControl>
+    where
+        T: Transport + 'static,
+        F: Fn() -> Result<T> + Send + Sync + 'static,
+    {
+        let device = connect()?;
+        let connector: Connector = Box::new(move || Ok(Box::new(connect()?) as Box<dyn Transport>));
+        self.build(Box::new(device), Some(connector), None)
+    }
+
+    /// Builds an `AirControl` replaying a recording instead of reading a device, see `AirControl::replay`.
+    ///
+    /// The required measurements and the poll interval do not apply, as the recorded readings are
+    /// delivered as they are.
+    pub fn replay(self, device: ReplayDevice) -> AirControl {
+        let aggregator = Aggregator::new(&self.required);
+        let mut monitor = Monitor::new(
+            Box::new(NoDevice),
+            FrameDecoder::new(DEFAULT_KEY),
+            None,
+            self.reconnect_policy,
+            aggregator,
+            self.poll_interval,
+            self.read_timeout,
+        );
+        monitor.replay = Some(device);
+        AirControl {
+            monitor: Arc::new(monitor),
+            info: None,
+            monitoring_thread: None,
+        }
+    }
+
+    fn build(self, device: Box<dyn Transport>, connector: Option<Connector>, info: Option<DeviceInfo>) -> Result<AirControl> {
+        let decoder = FrameDecoder::new(DEFAULT_KEY);
+        device.send_feature_report(&decoder.feature_report()).map_err(Error::FeatureReport)?;
+
+        let aggregator = Aggregator::new(&self.required);
+        let monitor = Monitor::new(device, decoder, connector, self.reconnect_policy, aggregator, self.poll_interval, self.read_timeout);
+        Ok(AirControl {
+            monitor: Arc::new(monitor),
+            info,
+            monitoring_thread: None,
+        })
+    }
+}
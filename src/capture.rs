@@ -0,0 +1,387 @@
+//! Capture of the raw reports of a device into a compact binary file.
+//!
+//! A `CaptureWriter` attached with `AirControl::start_capture` records every report exactly as it
+//! was read from the device, before it is decrypted or validated. `CaptureReader` reads such a
+//! capture back, and `CaptureReader::decode` re-runs the parsing of the crate over it, so the
+//! reports of a misbehaving device can be shared and analysed offline.
+//!
+//! # Format
+//! All integers are little endian. The file starts with a 16 byte header:
+//! - 4 bytes: The magic `ACAP`.
+//! - 2 bytes: The format version, currently `1`.
+//! - 8 bytes: The key sent to the device, needed to decrypt the reports of encrypting devices.
+//!   The header is written with the first report, once the writer was attached to a device.
+//! - 2 bytes: Reserved, zero.
+//!
+//! Every report follows as a record:
+//! - 8 bytes: The time the report was read, as signed nanoseconds since the Unix epoch.
+//! - 1 byte: The length of the report.
+//! - The bytes of the report.
+
+use crate::frame::{Frame, FrameDecoder, FrameEncoding, FrameError, LinkCounters, LinkStats, DEFAULT_KEY, FRAME_LENGTH};
+use crate::item::{ItemCode, RawValue};
+use crate::measurement::Measurement;
+use chrono::{DateTime, Utc};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"ACAP";
+const VERSION: u16 = 1;
+
+/// Writes reports into a capture file.
+///
+/// # Fields
+/// - `writer`: Where the capture is written.
+/// - `key`: The key written into the header, the key of the device once attached with `AirControl::start_capture`.
+/// - `header_written`: Whether the header was written, after which the key cannot change anymore.
+pub struct CaptureWriter {
+    writer: Box<dyn Write + Send>,
+    key: [u8; FRAME_LENGTH],
+    header_written: bool,
+}
+
+impl CaptureWriter {
+    /// Creates the capture file at `path`, replacing an existing file.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the file cannot be created.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Creates a capture written to `writer`, e.g. a socket or an in-memory buffer.
+    ///
+    /// The header is written with the first report or when the writer is dropped, so it carries
+    /// the key of the device the writer is attached to.
+    ///
+    /// # Errors
+    /// Does not fail currently, the errors of writing the header are returned by `write_report`.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        Ok(CaptureWriter { writer: Box::new(writer), key: DEFAULT_KEY, header_written: false })
+    }
+
+    /// Sets the key written into the header, unless the header was already written.
+    pub(crate) fn set_key(&mut self, key: [u8; FRAME_LENGTH]) {
+        if !self.header_written {
+            self.key = key;
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_all(MAGIC)?;
+            self.writer.write_all(&VERSION.to_le_bytes())?;
+            self.writer.write_all(&self.key)?;
+            self.writer.write_all(&[0, 0])?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Appends a report and flushes it, so the capture is complete even if the process is killed.
+    ///
+    /// # Parameters
+    /// - `time`: The time the report was read.
+    /// - `report`: The report as read from the device, at most 255 bytes.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the report cannot be written.
+    pub fn write_report(&mut self, time: DateTime<Utc>, report: &[u8]) -> io::Result<()> {
+        let len = u8::try_from(report.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "report is longer than 255 bytes"))?;
+        self.write_header()?;
+        self.writer.write_all(&time.timestamp_nanos_opt().unwrap_or_default().to_le_bytes())?;
+        self.writer.write_all(&[len])?;
+        self.writer.write_all(report)?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for CaptureWriter {
+    /// Writes the header of a capture without reports, so it can still be read.
+    fn drop(&mut self) {
+        if !self.header_written {
+            if let Err(error) = self.write_header().and_then(|()| self.writer.flush()) {
+                eprintln!("Error writing capture header: {}", error);
+            }
+        }
+    }
+}
+
+/// A report read from a capture.
+///
+/// # Fields
+/// - `time`: The time the report was read from the device.
+/// - `report`: The report as read from the device, possibly encrypted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedReport {
+    pub time: DateTime<Utc>,
+    pub report: Vec<u8>,
+}
+
+/// Reads the reports of a capture file, in the order they were read from the device.
+pub struct CaptureReader<R> {
+    reader: R,
+    key: [u8; FRAME_LENGTH],
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Opens the capture file at `path`.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the file cannot be opened and an error of kind `InvalidData` if it
+    /// is not a capture.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Reads a capture from `reader`.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the header cannot be read and an error of kind `InvalidData` if it
+    /// is not a capture of a supported version.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an AirControl capture"));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported capture version {}", version)));
+        }
+        let mut key = [0u8; FRAME_LENGTH];
+        key.copy_from_slice(&header[6..14]);
+        Ok(CaptureReader { reader, key })
+    }
+
+    /// Returns the key the device was initialized with.
+    pub fn key(&self) -> [u8; FRAME_LENGTH] {
+        self.key
+    }
+
+    /// Returns an iterator which decodes the reports like the monitoring thread does.
+    pub fn decode(self) -> DecodedReports<R> {
+        DecodedReports {
+            decoder: FrameDecoder::new(self.key),
+            link: LinkCounters::default(),
+            reports: self,
+        }
+    }
+
+    fn read_report(&mut self) -> io::Result<Option<CapturedReport>> {
+        // Only the end of the file before the first byte of a record is the end of the capture.
+        let mut time = [0u8; 8];
+        loop {
+            match self.reader.read(&mut time[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        self.reader.read_exact(&mut time[1..])?;
+        let mut len = [0u8; 1];
+        self.reader.read_exact(&mut len)?;
+        let mut report = vec![0u8; len[0] as usize];
+        self.reader.read_exact(&mut report)?;
+        let nanos = i64::from_le_bytes(time);
+        let time = DateTime::from_timestamp(nanos.div_euclid(1_000_000_000), nanos.rem_euclid(1_000_000_000) as u32).unwrap_or_default();
+        Ok(Some(CapturedReport { time, report }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CapturedReport>;
+
+    /// Returns the next report, `None` at the end of the capture. A truncated last record is
+    /// returned as an error of kind `UnexpectedEof`.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_report().transpose()
+    }
+}
+
+/// A captured report together with the result of its decoding.
+///
+/// # Fields
+/// - `time`: The time the report was read from the device.
+/// - `report`: The report as read from the device, possibly encrypted.
+/// - `frame`: The decoded frame, or why the report was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedReport {
+    pub time: DateTime<Utc>,
+    pub report: Vec<u8>,
+    pub frame: Result<Frame, FrameError>,
+}
+
+impl DecodedReport {
+    /// Returns the raw value of the frame, `None` if the report was rejected.
+    pub fn raw_value(&self) -> Option<RawValue> {
+        let frame = self.frame.as_ref().ok()?;
+        Some(RawValue { item: ItemCode::from_code(frame.item), value: frame.value, received_at: self.time })
+    }
+
+    /// Returns the measurement carried by the frame, `None` if the report was rejected or carries no known measurement.
+    pub fn measurement(&self) -> Option<Measurement> {
+        Measurement::from_frame(*self.frame.as_ref().ok()?, self.time)
+    }
+}
+
+/// Decodes the reports of a capture, created by `CaptureReader::decode`.
+pub struct DecodedReports<R> {
+    reports: CaptureReader<R>,
+    decoder: FrameDecoder,
+    link: LinkCounters,
+}
+
+impl<R> DecodedReports<R> {
+    /// Returns the counters of the reports decoded so far.
+    pub fn link_stats(&self) -> LinkStats {
+        self.link.snapshot()
+    }
+
+    /// Returns whether the device sent plaintext or encrypted reports, `None` while no report was accepted.
+    pub fn frame_encoding(&self) -> Option<FrameEncoding> {
+        self.decoder.encoding()
+    }
+}
+
+impl<R: Read> Iterator for DecodedReports<R> {
+    type Item = io::Result<DecodedReport>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let report = match self.reports.next()? {
+            Ok(report) => report,
+            Err(error) => return Some(Err(error)),
+        };
+        let frame = self.link.check(&self.decoder, &report.report);
+        Some(Ok(DecodedReport { time: report.time, report: report.report, frame }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame;
+    use crate::measurement::MeasurementKind;
+    use chrono::TimeZone;
+    use std::sync::{Arc, Mutex};
+
+    /// An in-memory file shared between the writer and the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(reports: &[(DateTime<Utc>, &[u8])]) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let mut writer = CaptureWriter::new(buffer.clone()).unwrap();
+        for (time, report) in reports {
+            writer.write_report(*time, report).unwrap();
+        }
+        drop(writer);
+        let bytes = buffer.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[test]
+    fn reads_back_written_reports() {
+        let first = Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap() + chrono::Duration::nanoseconds(123_456_789);
+        let second = Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap();
+        let bytes = capture(&[(first, &[1, 2, 3, 4, 5, 6, 7, 8]), (second, &[])]);
+        let reader = CaptureReader::new(bytes.as_slice()).unwrap();
+
+        assert_eq!(reader.key(), DEFAULT_KEY);
+        let reports: Vec<CapturedReport> = reader.map(Result::unwrap).collect();
+        assert_eq!(
+            reports,
+            [
+                CapturedReport { time: first, report: vec![1, 2, 3, 4, 5, 6, 7, 8] },
+                CapturedReport { time: second, report: vec![] },
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_plaintext_and_encrypted_reports() {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+        let co2 = Frame { item: ItemCode::Co2.code(), value: 650 }.to_bytes();
+        let encrypted = frame::encrypt(&DEFAULT_KEY, &Frame { item: ItemCode::Temperature.code(), value: 4711 }.to_bytes());
+        let bytes = capture(&[(time, &co2), (time, &co2[..5])]);
+        let mut reports = CaptureReader::new(bytes.as_slice()).unwrap().decode();
+
+        let report = reports.next().unwrap().unwrap();
+        assert_eq!(report.raw_value(), Some(RawValue { item: ItemCode::Co2, value: 650, received_at: time }));
+        assert_eq!(report.measurement().map(|measurement| measurement.kind), Some(MeasurementKind::Co2));
+        let report = reports.next().unwrap().unwrap();
+        assert_eq!(report.frame, Err(FrameError::Length { expected: FRAME_LENGTH, actual: 5 }));
+        assert!(reports.next().is_none());
+        assert_eq!(reports.frame_encoding(), Some(FrameEncoding::Plaintext));
+        assert_eq!(reports.link_stats().length_errors, 1);
+
+        let bytes = capture(&[(time, &encrypted)]);
+        let mut reports = CaptureReader::new(bytes.as_slice()).unwrap().decode();
+        assert_eq!(reports.next().unwrap().unwrap().measurement().map(|measurement| measurement.value), Some(21.29));
+        assert_eq!(reports.frame_encoding(), Some(FrameEncoding::Encrypted));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut bytes = capture(&[]);
+        bytes[0] = b'X';
+        assert_eq!(CaptureReader::new(bytes.as_slice()).err().map(|error| error.kind()), Some(io::ErrorKind::InvalidData));
+
+        let mut bytes = capture(&[]);
+        bytes[4] = 2;
+        assert_eq!(CaptureReader::new(bytes.as_slice()).err().map(|error| error.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn reports_a_truncated_record() {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+        let mut bytes = capture(&[(time, &[1, 2, 3, 4, 5, 6, 7, 8])]);
+        bytes.pop();
+        let mut reader = CaptureReader::new(bytes.as_slice()).unwrap();
+
+        assert_eq!(reader.next().unwrap().map_err(|error| error.kind()), Err(io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn reports_a_record_truncated_within_its_timestamp() {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+        let complete = capture(&[(time, &[1, 2, 3, 4, 5, 6, 7, 8])]);
+        for trailing in 1..8 {
+            let mut bytes = complete.clone();
+            bytes.extend_from_slice(&complete[16..16 + trailing]);
+            let mut reader = CaptureReader::new(bytes.as_slice()).unwrap();
+
+            assert!(reader.next().unwrap().is_ok());
+            assert_eq!(reader.next().unwrap().map_err(|error| error.kind()), Err(io::ErrorKind::UnexpectedEof), "{} trailing bytes", trailing);
+        }
+    }
+
+    #[test]
+    fn writes_the_key_of_the_device_into_the_header() {
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+        let buffer = SharedBuffer::default();
+        let mut writer = CaptureWriter::new(buffer.clone()).unwrap();
+        writer.set_key(key);
+        drop(writer);
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut reader = CaptureReader::new(bytes.as_slice()).unwrap();
+
+        assert_eq!(reader.key(), key);
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,70 @@
+//! Channel based consumption of the readings of a device.
+//!
+//! Besides callbacks, the monitoring thread feeds every reading into the channels created by
+//! `AirControl::subscribe` and `AirControl::subscribe_bounded`. `Readings` wraps such a channel
+//! into a blocking iterator.
+
+use crate::DeviceData;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
+
+/// Controls what happens with a reading when a bounded channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The monitoring thread waits until the receiver made space for the reading. A receiver which
+    /// is not drained stalls all other consumers and prevents the monitoring from stopping.
+    Block,
+    /// The reading is dropped for this receiver.
+    DropNewest,
+}
+
+/// The sending side of a channel registered at the monitoring thread.
+pub(crate) enum Subscriber {
+    Unbounded(Sender<DeviceData>),
+    Bounded(SyncSender<DeviceData>, OverflowPolicy),
+}
+
+impl Subscriber {
+    /// Sends a reading to the receiver.
+    ///
+    /// # Returns
+    /// `false` if the receiver was dropped and the subscriber can be removed.
+    pub(crate) fn send(&self, data: DeviceData) -> bool {
+        match self {
+            Subscriber::Unbounded(sender) => sender.send(data).is_ok(),
+            Subscriber::Bounded(sender, OverflowPolicy::Block) => sender.send(data).is_ok(),
+            Subscriber::Bounded(sender, OverflowPolicy::DropNewest) => match sender.try_send(data) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        }
+    }
+}
+
+/// A blocking iterator over the readings of a device.
+///
+/// Every call to `next` waits for the next reading. The iteration ends when the monitoring stops.
+pub struct Readings {
+    receiver: Receiver<DeviceData>,
+}
+
+impl Readings {
+    pub(crate) fn new(receiver: Receiver<DeviceData>) -> Self {
+        Readings { receiver }
+    }
+
+    /// Returns the next reading if one is already available, without blocking.
+    ///
+    /// # Returns
+    /// `None` if no reading is available or the monitoring stopped.
+    pub fn try_next(&mut self) -> Option<DeviceData> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Iterator for Readings {
+    type Item = DeviceData;
+
+    fn next(&mut self) -> Option<DeviceData> {
+        self.receiver.recv().ok()
+    }
+}
@@ -0,0 +1,145 @@
+//! Discovery of the AirControl devices attached to the system.
+//!
+//! `list_devices` enumerates all attached devices with their serial numbers and HID paths, which
+//! can be used to open a specific device with `AirControl::open_serial` or `AirControl::open_path`.
+
+use crate::error::{Error, Result};
+use crate::{PRODUCT_ID, VENDOR_ID};
+use hidapi::{HidApi, HidDevice};
+use std::ffi::CString;
+
+/// Describes an attached AirControl device.
+///
+/// # Fields
+/// - `path`: The platform specific HID path of the device, e.g. `/dev/hidraw0` on Linux.
+/// - `serial_number`: The serial number reported by the device, if any.
+/// - `manufacturer`: The manufacturer string reported by the device, if any.
+/// - `product`: The product string reported by the device, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+/// Lists all attached AirControl devices.
+///
+/// # Errors
+/// Returns `Error::Api` if the HID API instance cannot be created.
+pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+    let api = HidApi::new().map_err(Error::Api)?;
+    Ok(enumerate(&api))
+}
+
+/// Lists the AirControl devices known to `api`, skipping duplicate entries of the same path.
+pub(crate) fn enumerate(api: &HidApi) -> Vec<DeviceInfo> {
+    let mut devices: Vec<DeviceInfo> = Vec::new();
+    for info in api.device_list() {
+        if info.vendor_id() != VENDOR_ID || info.product_id() != PRODUCT_ID {
+            continue;
+        }
+        let path = info.path().to_string_lossy().into_owned();
+        if devices.iter().any(|device| device.path == path) {
+            continue;
+        }
+        devices.push(DeviceInfo {
+            path,
+            serial_number: info.serial_number().map(str::to_string),
+            manufacturer: info.manufacturer_string().map(str::to_string),
+            product: info.product_string().map(str::to_string),
+        });
+    }
+    devices
+}
+
+/// Opens the first attached device.
+///
+/// # Errors
+/// Returns `Error::Api` if the HID API instance cannot be created, `Error::DeviceNotFound` if no
+/// device is attached, `Error::InvalidPath` if its path contains a NUL byte and `Error::Open` if
+/// the device cannot be opened.
+pub(crate) fn open_first() -> Result<(HidDevice, DeviceInfo)> {
+    let api = HidApi::new().map_err(Error::Api)?;
+    let info = enumerate(&api).into_iter().next().ok_or(Error::DeviceNotFound)?;
+    open_info(&api, info)
+}
+
+/// Opens the only attached device matching `predicate`.
+///
+/// Several devices may report the same serial number, which is then rather the firmware version,
+/// so a selection matching more than one device is rejected instead of opening any of them.
+///
+/// # Errors
+/// Returns `Error::Api` if the HID API instance cannot be created, `Error::DeviceNotFound` if no
+/// attached device matches, `Error::AmbiguousDevice` if several attached devices match,
+/// `Error::InvalidPath` if the path of the device contains a NUL byte and `Error::Open` if the
+/// device cannot be opened.
+pub(crate) fn open(predicate: impl Fn(&DeviceInfo) -> bool) -> Result<(HidDevice, DeviceInfo)> {
+    let api = HidApi::new().map_err(Error::Api)?;
+    let info = select(enumerate(&api), predicate)?;
+    open_info(&api, info)
+}
+
+/// Returns the only device matching `predicate`.
+///
+/// # Errors
+/// Returns `Error::DeviceNotFound` if no device matches and `Error::AmbiguousDevice` if several devices match.
+fn select(devices: Vec<DeviceInfo>, predicate: impl Fn(&DeviceInfo) -> bool) -> Result<DeviceInfo> {
+    let mut matches: Vec<DeviceInfo> = devices.into_iter().filter(predicate).collect();
+    if matches.len() > 1 {
+        return Err(Error::AmbiguousDevice(matches.len()));
+    }
+    matches.pop().ok_or(Error::DeviceNotFound)
+}
+
+fn open_info(api: &HidApi, info: DeviceInfo) -> Result<(HidDevice, DeviceInfo)> {
+    let device = api.open_path(&hid_path(&info.path)?).map_err(Error::Open)?;
+    Ok((device, info))
+}
+
+/// Converts a device path to the form the HID API expects.
+///
+/// # Errors
+/// Returns `Error::InvalidPath` if the path contains a NUL byte.
+fn hid_path(path: &str) -> Result<CString> {
+    CString::new(path).map_err(Error::InvalidPath)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(path: &str, serial_number: &str) -> DeviceInfo {
+        DeviceInfo { path: path.to_string(), serial_number: Some(serial_number.to_string()), manufacturer: None, product: None }
+    }
+
+    #[test]
+    fn selects_the_only_match() {
+        let devices = vec![device("/dev/hidraw0", "1.40"), device("/dev/hidraw1", "2.00")];
+        let selected = select(devices, |info| info.serial_number.as_deref() == Some("2.00")).unwrap();
+        assert_eq!(selected.path, "/dev/hidraw1");
+    }
+
+    #[test]
+    fn rejects_ambiguous_selection() {
+        let devices = vec![device("/dev/hidraw0", "1.40"), device("/dev/hidraw1", "1.40")];
+        let result = select(devices.clone(), |info| info.serial_number.as_deref() == Some("1.40"));
+        assert!(matches!(result, Err(Error::AmbiguousDevice(2))), "{:?}", result);
+        assert!(select(devices, |info| info.path == "/dev/hidraw1").is_ok());
+    }
+
+    #[test]
+    fn reports_missing_device() {
+        let result = select(vec![device("/dev/hidraw0", "1.40")], |info| info.path == "/dev/hidraw1");
+        assert!(matches!(result, Err(Error::DeviceNotFound)), "{:?}", result);
+    }
+
+    #[test]
+    fn rejects_paths_with_nul_bytes() {
+        assert_eq!(hid_path("/dev/hidraw0").unwrap().as_bytes(), b"/dev/hidraw0");
+        let error = hid_path("/dev/hid\0raw0").unwrap_err();
+        assert!(matches!(error, Error::InvalidPath(_)), "{:?}", error);
+        assert!(std::error::Error::source(&error).is_some());
+    }
+}
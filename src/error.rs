@@ -0,0 +1,91 @@
+//! The error type of the crate.
+
+use crate::frame::FrameError;
+use hidapi::HidError;
+use std::ffi::NulError;
+use std::fmt;
+
+/// Errors which can occur while talking to an AirControl device.
+#[derive(Debug)]
+pub enum Error {
+    /// The HID API could not be initialized.
+    Api(HidError),
+    /// No AirControl device is attached.
+    DeviceNotFound,
+    /// The given number of attached devices match the selection, e.g. because they report the same serial number.
+    AmbiguousDevice(usize),
+    /// A device was found, but could not be opened, e.g. because of missing permissions.
+    Open(HidError),
+    /// The path of the device contains a NUL byte and cannot be passed to the HID API.
+    InvalidPath(NulError),
+    /// The initial feature report could not be sent to the device.
+    FeatureReport(HidError),
+    /// The device did not send a report within the read timeout.
+    ReadTimeout,
+    /// The device sent a report which failed the validation. Returned by the reads of
+    /// `AsyncAirControl`, the monitoring thread counts such reports in the `LinkStats` and skips them.
+    MalformedFrame(FrameError),
+    /// The device was disconnected or cannot be read anymore.
+    Disconnected(HidError),
+}
+
+/// A `Result` with the crate's `Error` as error type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Api(_) => write!(f, "Failed to create HID API instance"),
+            Error::DeviceNotFound => write!(f, "No AirControl device found"),
+            Error::AmbiguousDevice(count) => write!(f, "{} AirControl devices match, select one by its path", count),
+            Error::Open(_) => write!(f, "Failed to open device"),
+            Error::InvalidPath(_) => write!(f, "Invalid device path"),
+            Error::FeatureReport(_) => write!(f, "Failed to send feature report"),
+            Error::ReadTimeout => write!(f, "Timed out reading the device"),
+            Error::MalformedFrame(_) => write!(f, "Received a malformed frame"),
+            Error::Disconnected(_) => write!(f, "Could not read the device"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Api(source) | Error::Open(source) | Error::FeatureReport(source) | Error::Disconnected(source) => Some(source),
+            Error::MalformedFrame(source) => Some(source),
+            Error::InvalidPath(source) => Some(source),
+            Error::DeviceNotFound | Error::AmbiguousDevice(_) | Error::ReadTimeout => None,
+        }
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(error: FrameError) -> Self {
+        Error::MalformedFrame(error)
+    }
+}
+
+/// Formats an error followed by the chain of its sources, e.g. `Could not read the device: <HID error>`.
+pub(crate) fn with_sources(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        message.push_str(": ");
+        message.push_str(&error.to_string());
+        source = error.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_the_source_chain() {
+        let source = HidError::HidApiError { message: "device unplugged".to_string() };
+        let expected = format!("Could not read the device: {}", source);
+        assert_eq!(with_sources(&Error::Disconnected(source)), expected);
+        assert_eq!(with_sources(&Error::ReadTimeout), "Timed out reading the device");
+    }
+}
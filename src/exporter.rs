@@ -0,0 +1,403 @@
+//! A Prometheus exporter for the readings of devices, enabled by the `prometheus` feature.
+//!
+//! `MetricsExporter` collects the latest readings and link counters of the attached devices,
+//! labelled by their serial number, and renders them in the Prometheus text exposition format.
+//! The metrics are served on `/metrics` by a small HTTP server started with `serve`, or written to
+//! a file for the textfile collector of the node exporter after every update with `set_textfile`.
+//!
+//! ```no_run
+//! # use aircontrol::AirControl;
+//! # use aircontrol::exporter::MetricsExporter;
+//! let mut air_control = AirControl::new().unwrap();
+//! let exporter = MetricsExporter::new();
+//! exporter.attach(&air_control).detach();
+//! exporter.serve("0.0.0.0:9105").unwrap();
+//! air_control.start_monitoring();
+//! ```
+
+use crate::monitor::Monitor;
+use crate::reconnect::ConnectionState;
+use crate::subscription::Subscription;
+use crate::{lock, AirControl, DeviceData};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The time a scrape may take to send its request or to receive the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The metrics of a single attached device.
+///
+/// # Fields
+/// - `id`: Identifies the device within the exporter.
+/// - `serial`: The value of the `serial` label.
+/// - `monitor`: The state of the device, for the link counters and the connection state. The
+///   monitor owns the callback feeding the exporter, so it is not kept alive by the exporter.
+/// - `data`: The latest reading, `None` before the first one.
+#[derive(Clone)]
+struct DeviceMetrics {
+    id: u64,
+    serial: String,
+    monitor: Weak<Monitor>,
+    data: Option<DeviceData>,
+}
+
+/// # Fields
+/// - `devices`: The attached devices.
+/// - `next_id`: The id of the next attached device.
+/// - `textfile`: The file rewritten after every update, `None` if no file is written.
+#[derive(Default)]
+struct ExporterState {
+    devices: Vec<DeviceMetrics>,
+    next_id: u64,
+    textfile: Option<PathBuf>,
+}
+
+/// Removes a device from the exporter when the callback owning it is removed.
+struct Attachment {
+    exporter: MetricsExporter,
+    id: u64,
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        lock(&self.exporter.state).devices.retain(|device| device.id != self.id);
+    }
+}
+
+/// Exports the readings of `AirControl` devices as Prometheus metrics.
+///
+/// Cloning a `MetricsExporter` yields another handle to the same metrics.
+#[derive(Clone, Default)]
+pub struct MetricsExporter {
+    state: Arc<Mutex<ExporterState>>,
+}
+
+impl MetricsExporter {
+    /// Creates an exporter without any devices.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exports the readings of a device, labelled by its serial number.
+    ///
+    /// Devices without a serial number are labelled by their path, devices with a custom transport
+    /// by `unknown`. Use `attach_with_serial` to choose the label.
+    ///
+    /// # Returns
+    /// The subscription which removes the device from the exporter when it is cancelled or dropped.
+    /// A detached subscription keeps the device until the `AirControl` is dropped.
+    pub fn attach(&self, air_control: &AirControl) -> Subscription {
+        self.attach_with_serial(air_control, &air_control.device_name())
+    }
+
+    /// Exports the readings of a device with the given value of the `serial` label.
+    ///
+    /// # Returns
+    /// The subscription which removes the device from the exporter, see `attach`.
+    pub fn attach_with_serial(&self, air_control: &AirControl, serial: &str) -> Subscription {
+        let id = {
+            let mut state = lock(&self.state);
+            let id = state.next_id;
+            state.next_id += 1;
+            let monitor = Arc::downgrade(&air_control.monitor);
+            state.devices.push(DeviceMetrics { id, serial: serial.to_string(), monitor, data: None });
+            id
+        };
+        let attachment = Attachment { exporter: self.clone(), id };
+        air_control.register_callback(Box::new(move |data| attachment.exporter.update(attachment.id, data)))
+    }
+
+    /// Sets the file which is rewritten with the metrics after every update, `None` to stop writing it.
+    ///
+    /// The file is replaced atomically, as the textfile collector of the node exporter requires.
+    /// Errors while writing are reported on stderr.
+    pub fn set_textfile(&self, path: Option<&Path>) {
+        lock(&self.state).textfile = path.map(Path::to_path_buf);
+    }
+
+    /// Writes the metrics to a file for the textfile collector of the node exporter.
+    ///
+    /// The metrics are written to a temporary file next to `path`, which then replaces `path`.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the file cannot be written.
+    pub fn write_textfile(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, &self.render())
+    }
+
+    /// Renders the metrics of all attached devices in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        // The devices are rendered without holding the lock, as the last handle to a monitor may be
+        // dropped while rendering, which removes its device from the state.
+        let devices = lock(&self.state).devices.clone();
+        render(&devices)
+    }
+
+    /// Serves the metrics on `/metrics` over HTTP on a background thread.
+    ///
+    /// Every connection is answered on its own thread, so a slow or idle client does not delay
+    /// other scrapes.
+    ///
+    /// # Parameters
+    /// - `addr`: The address to listen on, e.g. `0.0.0.0:9105`.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the address cannot be bound.
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let exporter = self.clone();
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let exporter = exporter.clone();
+                    thread::spawn(move || {
+                        if let Err(error) = exporter.respond(stream) {
+                            eprintln!("Error serving metrics: {}", error);
+                        }
+                    });
+                }
+            })
+        };
+        Ok(MetricsServer { addr, running, thread: Some(thread) })
+    }
+
+    fn update(&self, id: u64, data: &DeviceData) {
+        let mut state = lock(&self.state);
+        if let Some(device) = state.devices.iter_mut().find(|device| device.id == id) {
+            device.data = Some(*data);
+        }
+        let Some(path) = state.textfile.clone() else {
+            return;
+        };
+        let devices = state.devices.clone();
+        drop(state);
+        let metrics = render(&devices);
+        if let Err(error) = write_atomically(&path, &metrics) {
+            eprintln!("Error writing metrics to {}: {}", path.display(), error);
+        }
+    }
+
+    /// Answers a single HTTP request.
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // The headers are not needed, but have to be read before the response is sent.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next().map(|target| target.split('?').next().unwrap_or_default())) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), Some("/")) => ("200 OK", "AirControl exporter, the metrics are served on /metrics\n".to_string()),
+            (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+/// The HTTP server started by `MetricsExporter::serve`.
+///
+/// The server runs until `stop` is called or the process exits, dropping the handle does not stop it.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting connections. Requests which are already being answered are finished on their own threads.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // The server only checks the flag when it accepts a connection.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Renders the metrics of the devices in the Prometheus text exposition format.
+///
+/// Devices whose `AirControl` was dropped are skipped.
+fn render(devices: &[DeviceMetrics]) -> String {
+    let devices: Vec<(&DeviceMetrics, Arc<Monitor>)> = devices.iter().filter_map(|device| Some((device, device.monitor.upgrade()?))).collect();
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    };
+    let label = |device: &DeviceMetrics| format!("serial=\"{}\"", escape_label(&device.serial));
+    let readings = |value: &dyn Fn(&DeviceData) -> Option<String>| -> Vec<(String, String)> {
+        devices.iter().filter_map(|(device, _)| Some((label(device), value(device.data.as_ref()?)?))).collect()
+    };
+    let links = |value: &dyn Fn(&Monitor) -> u64| -> Vec<(String, String)> {
+        devices.iter().map(|(device, monitor)| (label(device), value(monitor).to_string())).collect()
+    };
+
+    family("aircontrol_co2_ppm", "gauge", "The filtered CO2 concentration in parts per million.", readings(&|data| Some(data.co2().to_string())));
+    family("aircontrol_raw_co2_ppm", "gauge", "The unfiltered CO2 concentration in parts per million.", readings(&|data| data.raw_co2().map(|raw_co2| raw_co2.to_string())));
+    family("aircontrol_temperature_celsius", "gauge", "The ambient temperature in degrees Celsius.", readings(&|data| Some(data.temperature().to_string())));
+    family("aircontrol_humidity_percent", "gauge", "The relative humidity in percent.", readings(&|data| data.humidity().map(|humidity| humidity.to_string())));
+    family(
+        "aircontrol_last_reading_timestamp_seconds",
+        "gauge",
+        "The time of the latest reading in seconds since the Unix epoch.",
+        readings(&|data| Some(format!("{:.3}", data.time().timestamp_millis() as f64 / 1000.0))),
+    );
+    family(
+        "aircontrol_connected",
+        "gauge",
+        "Whether the device is connected.",
+        links(&|monitor| u64::from(*lock(&monitor.state) == ConnectionState::Connected)),
+    );
+    family("aircontrol_frames_received_total", "counter", "The number of reports read from the device.", links(&|monitor| monitor.link.snapshot().frames_received));
+    family("aircontrol_frames_accepted_total", "counter", "The number of reports which passed the validation.", links(&|monitor| monitor.link.snapshot().frames_accepted));
+    let rejected = devices.iter().flat_map(|(device, monitor)| {
+        let stats = monitor.link.snapshot();
+        [("length", stats.length_errors), ("checksum", stats.checksum_errors), ("terminator", stats.terminator_errors)]
+            .map(|(reason, count)| (format!("{},reason=\"{}\"", label(device), reason), count.to_string()))
+    }).collect();
+    family("aircontrol_frames_rejected_total", "counter", "The number of reports rejected by the validation.", rejected);
+    family("aircontrol_reconnects_total", "counter", "The number of times the device was re-opened after a disconnection.", links(&|monitor| monitor.link.snapshot().reconnects));
+    out
+}
+
+/// Escapes a label value of the Prometheus text exposition format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it to `path`.
+fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::ItemCode;
+    use crate::transport::ScriptedDevice;
+
+    fn air_control(co2: u16) -> AirControl {
+        let device = ScriptedDevice::new();
+        device.push_reading(ItemCode::Co2.code(), co2);
+        device.push_reading(ItemCode::Temperature.code(), 4711);
+        device.push_reading(ItemCode::Humidity.code(), 4520);
+        AirControl::with_transport(device).unwrap()
+    }
+
+    /// Runs the monitoring until the scripted readings were delivered.
+    fn run(air_control: &mut AirControl) {
+        let readings = air_control.readings();
+        air_control.start_monitoring();
+        for _ in readings {}
+    }
+
+    #[test]
+    fn renders_the_readings_of_attached_devices() {
+        let exporter = MetricsExporter::new();
+        let mut air_control = air_control(650);
+        let _subscription = exporter.attach_with_serial(&air_control, "1.40 \"a\"");
+        run(&mut air_control);
+
+        let metrics = exporter.render();
+        assert!(metrics.contains("# TYPE aircontrol_co2_ppm gauge\naircontrol_co2_ppm{serial=\"1.40 \\\"a\\\"\"} 650\n"), "{}", metrics);
+        assert!(metrics.contains("aircontrol_temperature_celsius{serial=\"1.40 \\\"a\\\"\"} 21.29\n"));
+        assert!(metrics.contains("aircontrol_humidity_percent{serial=\"1.40 \\\"a\\\"\"} 45.2\n"));
+        assert!(metrics.contains("aircontrol_frames_accepted_total{serial=\"1.40 \\\"a\\\"\"} 3\n"));
+        assert!(metrics.contains("aircontrol_frames_rejected_total{serial=\"1.40 \\\"a\\\"\",reason=\"checksum\"} 0\n"));
+        assert!(metrics.contains("aircontrol_connected{serial=\"1.40 \\\"a\\\"\"} 0\n"));
+        assert!(!metrics.contains("aircontrol_raw_co2_ppm{"));
+    }
+
+    #[test]
+    fn cancelling_the_subscription_removes_the_device() {
+        let exporter = MetricsExporter::new();
+        let first = air_control(650);
+        let second = air_control(700);
+        let subscription = exporter.attach(&first);
+        exporter.attach_with_serial(&second, "second").detach();
+        assert!(exporter.render().contains("aircontrol_connected{serial=\"unknown\"} 1\n"));
+
+        subscription.cancel();
+        let metrics = exporter.render();
+        assert!(!metrics.contains("serial=\"unknown\""));
+        assert!(metrics.contains("aircontrol_connected{serial=\"second\"} 1\n"));
+    }
+
+    #[test]
+    fn dropping_the_air_control_removes_the_device() {
+        let exporter = MetricsExporter::new();
+        let air_control = air_control(650);
+        exporter.attach(&air_control).detach();
+
+        drop(air_control);
+        assert!(lock(&exporter.state).devices.is_empty());
+        assert!(!exporter.render().contains("serial="));
+    }
+
+    #[test]
+    fn an_idle_client_does_not_block_other_scrapes() {
+        let exporter = MetricsExporter::new();
+        let mut server = exporter.serve("127.0.0.1:0").unwrap();
+        // The idle client connects first and never sends its request.
+        let _idle = TcpStream::connect(server.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let start = std::time::Instant::now();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.set_read_timeout(Some(REQUEST_TIMEOUT * 2)).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut status = String::new();
+        BufReader::new(&client).read_line(&mut status).unwrap();
+
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+        assert!(start.elapsed() < REQUEST_TIMEOUT, "the scrape took {:?}", start.elapsed());
+        server.stop();
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
@@ -0,0 +1,380 @@
+//! Parsing and validation of the 8 byte reports sent by an AirControl device.
+//!
+//! Every report carries an item code, a 16 bit big-endian value, a checksum over the first three
+//! bytes and a `0x0D` terminator. Reports failing any of these checks are rejected with a
+//! `FrameError` and counted in the `LinkStats` of the device.
+//!
+//! Devices with older firmware encrypt their reports with the key sent in the initial feature
+//! report. The `FrameDecoder` detects from the first valid report whether a device sends plaintext
+//! or encrypted reports and decrypts them transparently.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+/// The length of a report sent by the device in bytes.
+pub const FRAME_LENGTH: usize = 8;
+const FRAME_TERMINATOR: u8 = 0x0D;
+
+/// The key sent to the device in the initial feature report and used to decrypt its reports.
+pub const DEFAULT_KEY: [u8; FRAME_LENGTH] = [0xc4, 0xc6, 0xc0, 0x92, 0x40, 0x23, 0xdc, 0x96];
+const CIPHER_STATE: [u8; FRAME_LENGTH] = *b"Htemp99e";
+const CIPHER_SHUFFLE: [usize; FRAME_LENGTH] = [2, 4, 0, 7, 1, 6, 5, 3];
+
+/// The encoding of the reports sent by a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameEncoding {
+    /// The device sends its reports unencrypted.
+    Plaintext,
+    /// The device encrypts its reports with the key of the initial feature report.
+    Encrypted,
+}
+
+/// A single validated report of the device.
+///
+/// # Fields
+/// - `item`: The item code identifying the measurement, e.g. `0x50` for CO2.
+/// - `value`: The raw value of the measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub item: u8,
+    pub value: u16,
+}
+
+/// The reasons a report is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Fewer bytes than a full report were read.
+    Length { expected: usize, actual: usize },
+    /// The checksum byte does not match the sum of the first three bytes.
+    Checksum { expected: u8, actual: u8 },
+    /// The fifth byte is not the `0x0D` terminator.
+    Terminator { actual: u8 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Length { expected, actual } => write!(f, "Frame has {} bytes, expected {}", actual, expected),
+            FrameError::Checksum { expected, actual } => write!(f, "Frame checksum is {:#04x}, expected {:#04x}", actual, expected),
+            FrameError::Terminator { actual } => write!(f, "Frame terminator is {:#04x}, expected {:#04x}", actual, FRAME_TERMINATOR),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+impl Frame {
+    /// Parses and validates a report.
+    ///
+    /// # Parameters
+    /// - `buf`: The bytes actually read from the device.
+    ///
+    /// # Errors
+    /// Returns a `FrameError` if the report is too short, the checksum does not match or the terminator is missing.
+    pub fn parse(buf: &[u8]) -> Result<Frame, FrameError> {
+        if buf.len() < FRAME_LENGTH {
+            return Err(FrameError::Length { expected: FRAME_LENGTH, actual: buf.len() });
+        }
+        let expected = buf[0].wrapping_add(buf[1]).wrapping_add(buf[2]);
+        if buf[3] != expected {
+            return Err(FrameError::Checksum { expected, actual: buf[3] });
+        }
+        if buf[4] != FRAME_TERMINATOR {
+            return Err(FrameError::Terminator { actual: buf[4] });
+        }
+        Ok(Frame {
+            item: buf[0],
+            value: u16::from_be_bytes([buf[1], buf[2]]),
+        })
+    }
+
+    /// Returns the plaintext report carrying this frame, as sent by the device.
+    ///
+    /// The report contains the checksum over the first three bytes and the `0x0D` terminator.
+    pub fn to_bytes(&self) -> [u8; FRAME_LENGTH] {
+        let [high, low] = self.value.to_be_bytes();
+        let checksum = self.item.wrapping_add(high).wrapping_add(low);
+        [self.item, high, low, checksum, FRAME_TERMINATOR, 0x00, 0x00, 0x00]
+    }
+}
+
+/// Decrypts a report of a device with encrypting firmware.
+///
+/// # Parameters
+/// - `key`: The key sent to the device in the initial feature report.
+/// - `data`: The encrypted report.
+pub fn decrypt(key: &[u8; FRAME_LENGTH], data: &[u8; FRAME_LENGTH]) -> [u8; FRAME_LENGTH] {
+    let mut shuffled = [0u8; FRAME_LENGTH];
+    for (i, &position) in CIPHER_SHUFFLE.iter().enumerate() {
+        shuffled[position] = data[i];
+    }
+    let mut xored = [0u8; FRAME_LENGTH];
+    for i in 0..FRAME_LENGTH {
+        xored[i] = shuffled[i] ^ key[i];
+    }
+    let mut out = [0u8; FRAME_LENGTH];
+    for i in 0..FRAME_LENGTH {
+        let rotated = (xored[i] >> 3) | (xored[(i + FRAME_LENGTH - 1) % FRAME_LENGTH] << 5);
+        out[i] = rotated.wrapping_sub(CIPHER_STATE[i].rotate_left(4));
+    }
+    out
+}
+
+/// Encrypts a report the way a device with encrypting firmware does. This is the inverse of `decrypt`.
+///
+/// # Parameters
+/// - `key`: The key sent to the device in the initial feature report.
+/// - `data`: The plaintext report.
+pub fn encrypt(key: &[u8; FRAME_LENGTH], data: &[u8; FRAME_LENGTH]) -> [u8; FRAME_LENGTH] {
+    let mut rotated = [0u8; FRAME_LENGTH];
+    for i in 0..FRAME_LENGTH {
+        rotated[i] = data[i].wrapping_add(CIPHER_STATE[i].rotate_left(4));
+    }
+    let mut out = [0u8; FRAME_LENGTH];
+    for (i, &position) in CIPHER_SHUFFLE.iter().enumerate() {
+        let xored = (rotated[position] << 3) | (rotated[(position + 1) % FRAME_LENGTH] >> 5);
+        out[i] = xored ^ key[position];
+    }
+    out
+}
+
+/// Decodes reports of a device, detecting whether they are encrypted.
+///
+/// Until the first valid report was received, every report is checked as plaintext first and,
+/// if that fails, decrypted and checked again. The encoding of the first valid report is used for
+/// all following reports.
+pub(crate) struct FrameDecoder {
+    key: [u8; FRAME_LENGTH],
+    encoding: AtomicU8,
+}
+
+const ENCODING_UNKNOWN: u8 = 0;
+const ENCODING_PLAINTEXT: u8 = 1;
+const ENCODING_ENCRYPTED: u8 = 2;
+
+impl FrameDecoder {
+    pub(crate) fn new(key: [u8; FRAME_LENGTH]) -> Self {
+        FrameDecoder { key, encoding: AtomicU8::new(ENCODING_UNKNOWN) }
+    }
+
+    /// Returns the key the reports are decrypted with.
+    pub(crate) fn key(&self) -> [u8; FRAME_LENGTH] {
+        self.key
+    }
+
+    /// Returns the report to send to the device to initialize it with the key of this decoder.
+    pub(crate) fn feature_report(&self) -> [u8; FRAME_LENGTH + 1] {
+        let mut report = [0u8; FRAME_LENGTH + 1];
+        report[1..].copy_from_slice(&self.key);
+        report
+    }
+
+    /// Returns the detected encoding, `None` if no valid report was received yet.
+    pub(crate) fn encoding(&self) -> Option<FrameEncoding> {
+        match self.encoding.load(Ordering::Relaxed) {
+            ENCODING_PLAINTEXT => Some(FrameEncoding::Plaintext),
+            ENCODING_ENCRYPTED => Some(FrameEncoding::Encrypted),
+            _ => None,
+        }
+    }
+
+    /// Forgets the detected encoding, e.g. after the device was re-opened.
+    pub(crate) fn reset(&self) {
+        self.encoding.store(ENCODING_UNKNOWN, Ordering::Relaxed);
+    }
+
+    /// Decodes a report read from the device.
+    ///
+    /// # Errors
+    /// Returns a `FrameError` if the report is invalid in the detected encoding. While the encoding is
+    /// still unknown, the error of the plaintext check is returned.
+    pub(crate) fn decode(&self, buf: &[u8]) -> Result<Frame, FrameError> {
+        let Ok(data) = <&[u8; FRAME_LENGTH]>::try_from(buf) else {
+            return Frame::parse(buf);
+        };
+        match self.encoding() {
+            Some(FrameEncoding::Plaintext) => Frame::parse(data),
+            Some(FrameEncoding::Encrypted) => Frame::parse(&decrypt(&self.key, data)),
+            None => {
+                let error = match Frame::parse(data) {
+                    Ok(frame) => {
+                        self.encoding.store(ENCODING_PLAINTEXT, Ordering::Relaxed);
+                        return Ok(frame);
+                    }
+                    Err(error) => error,
+                };
+                let frame = Frame::parse(&decrypt(&self.key, data)).map_err(|_| error)?;
+                self.encoding.store(ENCODING_ENCRYPTED, Ordering::Relaxed);
+                Ok(frame)
+            }
+        }
+    }
+}
+
+/// A snapshot of the link quality counters of a device.
+///
+/// # Fields
+/// - `frames_received`: The number of non-empty reports read from the device.
+/// - `frames_accepted`: The number of reports which passed the validation.
+/// - `length_errors`: The number of reports rejected because they were too short.
+/// - `checksum_errors`: The number of reports rejected because of a checksum mismatch.
+/// - `terminator_errors`: The number of reports rejected because of a missing terminator.
+/// - `reconnects`: The number of times the device was re-opened after a disconnection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub frames_received: u64,
+    pub frames_accepted: u64,
+    pub length_errors: u64,
+    pub checksum_errors: u64,
+    pub terminator_errors: u64,
+    pub reconnects: u64,
+}
+
+impl LinkStats {
+    /// Returns the total number of rejected reports.
+    pub fn frames_rejected(&self) -> u64 {
+        self.length_errors + self.checksum_errors + self.terminator_errors
+    }
+}
+
+/// The counters behind `LinkStats`, shared between the monitoring thread and the `AirControl` handle.
+#[derive(Default)]
+pub(crate) struct LinkCounters {
+    frames_received: AtomicU64,
+    frames_accepted: AtomicU64,
+    length_errors: AtomicU64,
+    checksum_errors: AtomicU64,
+    terminator_errors: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+impl LinkCounters {
+    /// Decodes and validates a report read from the device and updates the counters accordingly.
+    pub(crate) fn check(&self, decoder: &FrameDecoder, buf: &[u8]) -> Result<Frame, FrameError> {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        let result = decoder.decode(buf);
+        let counter = match result {
+            Ok(_) => &self.frames_accepted,
+            Err(FrameError::Length { .. }) => &self.length_errors,
+            Err(FrameError::Checksum { .. }) => &self.checksum_errors,
+            Err(FrameError::Terminator { .. }) => &self.terminator_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    /// Counts a successful reconnection.
+    pub(crate) fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LinkStats {
+        LinkStats {
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            frames_accepted: self.frames_accepted.load(Ordering::Relaxed),
+            length_errors: self.length_errors.load(Ordering::Relaxed),
+            checksum_errors: self.checksum_errors.load(Ordering::Relaxed),
+            terminator_errors: self.terminator_errors.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_frame() {
+        let frame = Frame::parse(&[0x50, 0x03, 0x2a, 0x7d, 0x0d, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(frame, Frame { item: 0x50, value: 810 });
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let frame = Frame { item: 0x42, value: 4711 };
+        assert_eq!(Frame::parse(&frame.to_bytes()), Ok(frame));
+    }
+
+    #[test]
+    fn rejects_short_frame() {
+        assert_eq!(Frame::parse(&[0x50, 0x03, 0x2a]), Err(FrameError::Length { expected: 8, actual: 3 }));
+    }
+
+    #[test]
+    fn rejects_wrong_checksum() {
+        let mut bytes = Frame { item: 0x50, value: 810 }.to_bytes();
+        bytes[3] = bytes[3].wrapping_add(1);
+        assert_eq!(Frame::parse(&bytes), Err(FrameError::Checksum { expected: 0x7d, actual: 0x7e }));
+    }
+
+    #[test]
+    fn rejects_missing_terminator() {
+        let mut bytes = Frame { item: 0x50, value: 810 }.to_bytes();
+        bytes[4] = 0x00;
+        assert_eq!(Frame::parse(&bytes), Err(FrameError::Terminator { actual: 0x00 }));
+    }
+
+    #[test]
+    fn decrypt_inverts_encrypt() {
+        let data = Frame { item: 0x41, value: 4500 }.to_bytes();
+        let encrypted = encrypt(&DEFAULT_KEY, &data);
+        assert_ne!(encrypted, data);
+        assert_eq!(decrypt(&DEFAULT_KEY, &encrypted), data);
+    }
+
+    #[test]
+    fn decoder_detects_plaintext() {
+        let decoder = FrameDecoder::new(DEFAULT_KEY);
+        let frame = Frame { item: 0x50, value: 650 };
+        assert_eq!(decoder.encoding(), None);
+        assert_eq!(decoder.decode(&frame.to_bytes()), Ok(frame));
+        assert_eq!(decoder.encoding(), Some(FrameEncoding::Plaintext));
+    }
+
+    #[test]
+    fn decoder_detects_encryption() {
+        let decoder = FrameDecoder::new(DEFAULT_KEY);
+        let frame = Frame { item: 0x50, value: 650 };
+        assert_eq!(decoder.decode(&encrypt(&DEFAULT_KEY, &frame.to_bytes())), Ok(frame));
+        assert_eq!(decoder.encoding(), Some(FrameEncoding::Encrypted));
+        // Once detected, plaintext reports are decrypted as well and fail the validation.
+        assert!(decoder.decode(&frame.to_bytes()).is_err());
+
+        decoder.reset();
+        assert_eq!(decoder.encoding(), None);
+        assert_eq!(decoder.decode(&frame.to_bytes()), Ok(frame));
+    }
+
+    #[test]
+    fn feature_report_carries_key() {
+        let report = FrameDecoder::new(DEFAULT_KEY).feature_report();
+        assert_eq!(report[0], 0x00);
+        assert_eq!(report[1..], DEFAULT_KEY);
+    }
+
+    #[test]
+    fn link_counters_count_rejections() {
+        let decoder = FrameDecoder::new(DEFAULT_KEY);
+        let link = LinkCounters::default();
+        let valid = Frame { item: 0x50, value: 650 }.to_bytes();
+        let mut checksum = valid;
+        checksum[3] ^= 0xff;
+        let mut terminator = valid;
+        terminator[4] = 0x00;
+
+        assert!(link.check(&decoder, &valid).is_ok());
+        assert!(link.check(&decoder, &valid[..4]).is_err());
+        assert!(link.check(&decoder, &checksum).is_err());
+        assert!(link.check(&decoder, &terminator).is_err());
+        link.reconnected();
+
+        let stats = link.snapshot();
+        assert_eq!(
+            stats,
+            LinkStats { frames_received: 4, frames_accepted: 1, length_errors: 1, checksum_errors: 1, terminator_errors: 1, reconnects: 1 }
+        );
+        assert_eq!(stats.frames_rejected(), 3);
+    }
+}
@@ -0,0 +1,696 @@
+//! Output of sensor data in the InfluxDB line protocol.
+//!
+//! `LineProtocol` turns a `DeviceData` into a line like
+//! `aircontrol,device=1234 co2=812i,temperature=21.5,humidity=40.2 1714566600000000000`, with the
+//! time of the reading as nanosecond timestamp. An `InfluxSink` writes these lines to stdout for
+//! the `execd` input of Telegraf, to any writer, to a UDP socket, or to the write API of
+//! InfluxDB v2 over HTTP. It is configured with an `InfluxSinkBuilder` and attached to an
+//! `AirControl` with `AirControl::record_influx`:
+//!
+//! ```no_run
+//! # use aircontrol::AirControl;
+//! # use aircontrol::influx::InfluxSink;
+//! let air_control = AirControl::new().unwrap();
+//! let sink = InfluxSink::http("http://localhost:8086", "home", "environment", Some("my-token"))
+//!     .tag("room", "office")
+//!     .open()
+//!     .unwrap();
+//! air_control.record_influx(sink).detach();
+//! ```
+
+use crate::DeviceData;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The maximum size of a UDP datagram, small enough to avoid fragmentation on common networks.
+const MAX_DATAGRAM: usize = 1400;
+/// The time to wait for the InfluxDB server when connecting, sending or receiving.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// The number of lines kept for another attempt after a batch could not be written.
+const MAX_PENDING: usize = 10_000;
+/// The number of lines waiting for the worker thread before `InfluxSink::write` drops readings.
+const QUEUE_SIZE: usize = 1000;
+/// The time to wait before the first retry of a batch which could not be written.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest time to wait between two retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Formats `DeviceData` in the InfluxDB line protocol.
+///
+/// The fields are `co2` and `raw_co2` as integers, `temperature` in degrees Celsius and `humidity`
+/// in percent as floats. Values the reading does not carry are omitted.
+///
+/// # Fields
+/// - `measurement`: The name of the measurement.
+/// - `tags`: The tags added to every line, sorted by key as InfluxDB recommends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineProtocol {
+    measurement: String,
+    tags: Vec<(String, String)>,
+}
+
+impl Default for LineProtocol {
+    /// Returns the format with the measurement `aircontrol` and no tags.
+    fn default() -> Self {
+        Self::new("aircontrol")
+    }
+}
+
+impl LineProtocol {
+    /// Creates the format with the given measurement name and no tags.
+    pub fn new(measurement: &str) -> Self {
+        LineProtocol { measurement: measurement.to_string(), tags: Vec::new() }
+    }
+
+    /// Adds a tag to every line, e.g. the serial number of the device. An existing tag with the same key is replaced.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.retain(|(existing, _)| existing != key);
+        self.tags.push((key.to_string(), value.to_string()));
+        self.tags.sort();
+        self
+    }
+
+    /// Returns the measurement name.
+    pub fn measurement(&self) -> &str {
+        &self.measurement
+    }
+
+    /// Returns the tags, sorted by key.
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
+    }
+
+    /// Formats a reading as a single line, without the trailing newline.
+    pub fn format(&self, data: &DeviceData) -> String {
+        let mut line = escape(&self.measurement, &[',', ' ']);
+        for (key, value) in &self.tags {
+            // Tags with an empty value are not valid in the line protocol.
+            if !value.is_empty() {
+                let _ = write!(line, ",{}={}", escape(key, &[',', '=', ' ']), escape(value, &[',', '=', ' ']));
+            }
+        }
+        let _ = write!(line, " co2={}i", data.co2());
+        if let Some(raw_co2) = data.raw_co2() {
+            let _ = write!(line, ",raw_co2={}i", raw_co2);
+        }
+        let _ = write!(line, ",temperature={}", data.temperature());
+        if let Some(humidity) = data.humidity() {
+            let _ = write!(line, ",humidity={}", humidity);
+        }
+        if let Some(nanos) = data.time().timestamp_nanos_opt() {
+            let _ = write!(line, " {}", nanos);
+        }
+        line
+    }
+}
+
+/// Escapes the given characters and backslashes of a name, tag or field key.
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Where an `InfluxSink` writes the lines.
+enum Target {
+    Writer(Box<dyn Write + Send>),
+    Udp(String),
+    Http { url: String, org: String, bucket: String, token: Option<String> },
+}
+
+/// Configures an `InfluxSink`, created by `InfluxSink::stdout`, `InfluxSink::writer`,
+/// `InfluxSink::udp` and `InfluxSink::http`.
+///
+/// # Fields
+/// - `target`: Where the lines are written.
+/// - `protocol`: The format of the lines.
+/// - `batch_size`: The number of lines collected before they are written.
+/// - `flush_interval`: The maximum age of the oldest collected line before the batch is written.
+pub struct InfluxSinkBuilder {
+    target: Target,
+    protocol: LineProtocol,
+    batch_size: usize,
+    flush_interval: Duration,
+}
+
+impl InfluxSinkBuilder {
+    fn new(target: Target, batch_size: usize) -> Self {
+        InfluxSinkBuilder { target, protocol: LineProtocol::default(), batch_size, flush_interval: Duration::from_secs(10) }
+    }
+
+    /// Sets the measurement name, by default `aircontrol`.
+    pub fn measurement(mut self, measurement: &str) -> Self {
+        self.protocol.measurement = measurement.to_string();
+        self
+    }
+
+    /// Adds a tag to every line, e.g. `tag("device", serial)`.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.protocol = self.protocol.tag(key, value);
+        self
+    }
+
+    /// Sets the format of the lines, replacing the measurement name and tags set before.
+    pub fn line_protocol(mut self, protocol: LineProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the number of lines collected before they are written, by default 1 for writers and
+    /// UDP and 100 for HTTP. Values below 1 are taken as 1.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets the maximum age of the oldest collected line, by default 10 seconds. A batch which is
+    /// not full is written when its oldest line reaches this age.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Creates the sink and starts its worker thread.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the UDP socket cannot be bound or its address cannot be resolved,
+    /// or the worker thread cannot be started, and an error of kind `InvalidInput` if the URL of
+    /// the HTTP target is not a plain `http://` URL.
+    pub fn open(self) -> io::Result<InfluxSink> {
+        let output = match self.target {
+            Target::Writer(writer) => Output::Writer(writer),
+            Target::Udp(addr) => {
+                let addr = addr.to_socket_addrs()?.next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot resolve {}", addr)))?;
+                let bind: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                Output::Udp(socket)
+            }
+            Target::Http { url, org, bucket, token } => {
+                let (host, base) = parse_url(&url)?;
+                let path = format!("{}/api/v2/write?org={}&bucket={}&precision=ns", base, encode_query(&org), encode_query(&bucket));
+                Output::Http { host, path, token }
+            }
+        };
+        let worker = Worker {
+            output,
+            batch_size: self.batch_size,
+            flush_interval: self.flush_interval,
+            pending: VecDeque::new(),
+            oldest: None,
+            backoff: INITIAL_BACKOFF,
+            retry_at: None,
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let handle = thread::Builder::new().name("influx-sink".to_string()).spawn(move || worker.run(receiver))?;
+        Ok(InfluxSink { protocol: self.protocol, sender: Some(sender), worker: Some(handle) })
+    }
+}
+
+/// The opened target of an `InfluxSink`.
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    Udp(UdpSocket),
+    Http { host: String, path: String, token: Option<String> },
+}
+
+/// A request to the worker thread of an `InfluxSink`.
+enum Command {
+    /// A formatted line to write with the next batch.
+    Line(String),
+    /// Writes the collected lines now and sends back the result.
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+
+/// Writes sensor data in the InfluxDB line protocol, collecting the lines into batches.
+///
+/// The lines are handed to a worker thread through a bounded queue, so `write` never waits for
+/// the target. The worker writes a batch when it is full or its oldest line reaches the flush
+/// interval. A batch which could not be written is kept and retried with a backoff that doubles
+/// from 1 second up to 1 minute, up to a limit after which the oldest lines are dropped. The
+/// remaining lines are written when the sink is dropped.
+///
+/// # Fields
+/// - `protocol`: The format of the lines.
+/// - `sender`: The queue to the worker thread, `None` once the sink is dropped.
+/// - `worker`: The worker thread writing the lines.
+pub struct InfluxSink {
+    protocol: LineProtocol,
+    sender: Option<SyncSender<Command>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl InfluxSink {
+    /// Returns a builder for a sink writing to stdout, e.g. for the `execd` input of Telegraf.
+    pub fn stdout() -> InfluxSinkBuilder {
+        Self::writer(io::stdout())
+    }
+
+    /// Returns a builder for a sink writing to `writer`, which is flushed after every batch.
+    pub fn writer(writer: impl Write + Send + 'static) -> InfluxSinkBuilder {
+        InfluxSinkBuilder::new(Target::Writer(Box::new(writer)), 1)
+    }
+
+    /// Returns a builder for a sink sending datagrams to the UDP listener at `addr`, e.g. the
+    /// `socket_listener` input of Telegraf.
+    pub fn udp(addr: &str) -> InfluxSinkBuilder {
+        InfluxSinkBuilder::new(Target::Udp(addr.to_string()), 1)
+    }
+
+    /// Returns a builder for a sink posting to the write API of InfluxDB v2.
+    ///
+    /// # Parameters
+    /// - `url`: The URL of the server, e.g. `http://localhost:8086`. HTTPS is not supported.
+    /// - `org`: The organization of the bucket.
+    /// - `bucket`: The bucket the readings are written to.
+    /// - `token`: The API token, `None` for servers without authentication.
+    pub fn http(url: &str, org: &str, bucket: &str, token: Option<&str>) -> InfluxSinkBuilder {
+        let target = Target::Http {
+            url: url.to_string(),
+            org: org.to_string(),
+            bucket: bucket.to_string(),
+            token: token.map(str::to_string),
+        };
+        InfluxSinkBuilder::new(target, 100)
+    }
+
+    /// Returns the format of the lines.
+    pub fn line_protocol(&self) -> &LineProtocol {
+        &self.protocol
+    }
+
+    /// Queues a reading for the worker thread without waiting for it to be written.
+    ///
+    /// # Errors
+    /// Returns an error of kind `WouldBlock` if the queue is full because the worker thread is
+    /// still busy with the target, in which case the reading is dropped, and an error of kind
+    /// `BrokenPipe` if the worker thread stopped.
+    pub fn write(&mut self, data: &DeviceData) -> io::Result<()> {
+        let sender = self.sender.as_ref().ok_or_else(worker_stopped)?;
+        match sender.try_send(Command::Line(self.protocol.format(data))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(io::ErrorKind::WouldBlock, "the queue of the line protocol writer is full, reading dropped")),
+            Err(TrySendError::Disconnected(_)) => Err(worker_stopped()),
+        }
+    }
+
+    /// Writes the collected lines now and waits until they are written, without waiting for a
+    /// pending retry.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the lines cannot be written, or the error response of the server.
+    /// The lines are kept for the next attempt.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let sender = self.sender.as_ref().ok_or_else(worker_stopped)?;
+        let (reply, result) = mpsc::channel();
+        sender.send(Command::Flush(reply)).map_err(|_| worker_stopped())?;
+        result.recv().map_err(|_| worker_stopped())?
+    }
+}
+
+impl Drop for InfluxSink {
+    fn drop(&mut self) {
+        // Closing the queue makes the worker write the remaining lines and stop.
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The error returned when the worker thread of an `InfluxSink` is not running.
+fn worker_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the line protocol writer stopped")
+}
+
+/// The worker thread of an `InfluxSink`, which collects the lines and writes them in batches.
+///
+/// # Fields
+/// - `output`: Where the lines are written.
+/// - `batch_size`: The number of lines collected before they are written.
+/// - `flush_interval`: The maximum age of the oldest collected line before the batch is written.
+/// - `pending`: The lines not written yet.
+/// - `oldest`: The time the oldest pending line was collected.
+/// - `backoff`: The time to wait before the next retry after a failed write.
+/// - `retry_at`: The time of the next attempt after a failed write, `None` if the last write succeeded.
+struct Worker {
+    output: Output,
+    batch_size: usize,
+    flush_interval: Duration,
+    pending: VecDeque<String>,
+    oldest: Option<Instant>,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Worker {
+    /// Handles the commands until the sink is dropped, then writes the remaining lines.
+    fn run(mut self, receiver: Receiver<Command>) {
+        loop {
+            let command = match self.deadline() {
+                Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match command {
+                Ok(Command::Line(line)) => {
+                    if self.pending.len() >= MAX_PENDING {
+                        self.pending.pop_front();
+                    }
+                    self.pending.push_back(line);
+                    self.oldest.get_or_insert_with(Instant::now);
+                    if self.is_due() {
+                        self.write_batch();
+                    }
+                }
+                Ok(Command::Flush(reply)) => {
+                    let _ = reply.send(self.flush());
+                }
+                Err(RecvTimeoutError::Timeout) => self.write_batch(),
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Err(error) = self.flush() {
+                        eprintln!("Error writing line protocol: {}", error);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns the time the collected lines are due, `None` if there are none.
+    fn deadline(&self) -> Option<Instant> {
+        let oldest = self.oldest?;
+        Some(self.retry_at.unwrap_or(oldest + self.flush_interval))
+    }
+
+    /// Returns whether the collected lines should be written now: the batch is full, its oldest
+    /// line reached the flush interval, or a retry is due.
+    fn is_due(&self) -> bool {
+        let full = self.retry_at.is_none() && self.pending.len() >= self.batch_size;
+        full || self.deadline().is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Writes the collected lines and schedules a retry if they cannot be written.
+    fn write_batch(&mut self) {
+        if let Err(error) = self.flush() {
+            eprintln!("Error writing line protocol, retrying in {} s: {}", self.backoff.as_secs(), error);
+            self.retry_at = Some(Instant::now() + self.backoff);
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Writes the collected lines, keeping them if they cannot be written.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let lines: Vec<&str> = self.pending.iter().map(String::as_str).collect();
+        match &mut self.output {
+            Output::Writer(writer) => {
+                for line in &lines {
+                    writeln!(writer, "{}", line)?;
+                }
+                writer.flush()?;
+            }
+            Output::Udp(socket) => send_datagrams(socket, &lines)?,
+            Output::Http { host, path, token } => post(host, path, token.as_deref(), &lines)?,
+        }
+        self.pending.clear();
+        self.oldest = None;
+        self.backoff = INITIAL_BACKOFF;
+        self.retry_at = None;
+        Ok(())
+    }
+}
+
+/// Sends the lines in as few datagrams as possible. A line longer than a datagram is sent alone.
+fn send_datagrams(socket: &UdpSocket, lines: &[&str]) -> io::Result<()> {
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+            socket.send(datagram.as_bytes())?;
+            datagram.clear();
+        }
+        datagram.push_str(line);
+        datagram.push('\n');
+    }
+    socket.send(datagram.as_bytes())?;
+    Ok(())
+}
+
+/// Posts the lines to the write API and checks the response.
+fn post(host: &str, path: &str, token: Option<&str>, lines: &[&str]) -> io::Result<()> {
+    let body = lines.join("\n");
+    let addr = host.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot resolve {}", host)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    let mut request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\n", path, host);
+    if let Some(token) = token {
+        let _ = write!(request, "Authorization: Token {}\r\n", token);
+    }
+    let _ = write!(request, "Content-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let code = status.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid HTTP response `{}`", status.trim_end())))?;
+    if (200..300).contains(&code) {
+        return Ok(());
+    }
+    // The body explains the error, e.g. a wrong token or a missing bucket.
+    let mut response = String::new();
+    let _ = reader.read_to_string(&mut response);
+    let message = response.split("\r\n\r\n").nth(1).unwrap_or_default().trim();
+    Err(io::Error::other(format!("InfluxDB responded with {}: {}", status.trim_end(), message)))
+}
+
+/// Splits an `http://` URL into the host with port and the path without trailing slash.
+fn parse_url(url: &str) -> io::Result<(String, String)> {
+    let rest = url.strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported URL `{}`, only http:// is supported", url)))?;
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if host.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("missing host in URL `{}`", url)));
+    }
+    let host = if host.rsplit_once(':').is_some_and(|(_, port)| !port.is_empty() && !port.contains(']')) {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((host, path.trim_end_matches('/').to_string()))
+}
+
+/// Percent-encodes a query parameter value.
+fn encode_query(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// An in-memory writer shared between the sink and the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reading() -> DeviceData {
+        DeviceData::new(Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap(), 812, 21.5, Some(40.25))
+    }
+
+    /// Accepts one connection, reads the request and answers with `response`.
+    fn serve_once(listener: TcpListener, response: &'static str) -> JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            request
+        })
+    }
+
+    #[test]
+    fn formats_fields_tags_and_timestamp() {
+        let protocol = LineProtocol::new("air quality").tag("room", "living room").tag("device", "a,b=c");
+        let line = protocol.format(&reading().with_raw_co2(Some(830)));
+        assert_eq!(
+            line,
+            r"air\ quality,device=a\,b\=c,room=living\ room co2=812i,raw_co2=830i,temperature=21.5,humidity=40.25 1714566600000000000"
+        );
+    }
+
+    #[test]
+    fn omits_missing_values_and_empty_tags() {
+        let protocol = LineProtocol::default().tag("device", "");
+        let line = protocol.format(&DeviceData::new(Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap(), 650, 20.0, None));
+        assert_eq!(line, "aircontrol co2=650i,temperature=20 1714566600000000000");
+    }
+
+    #[test]
+    fn replaces_tags_with_the_same_key() {
+        let protocol = LineProtocol::default().tag("room", "office").tag("room", "kitchen");
+        assert_eq!(protocol.tags(), &[("room".to_string(), "kitchen".to_string())]);
+    }
+
+    #[test]
+    fn writes_batches_to_a_writer() {
+        let buffer = SharedBuffer::default();
+        let mut sink = InfluxSink::writer(buffer.clone()).batch_size(2).flush_interval(Duration::from_secs(3600)).open().unwrap();
+        sink.write(&reading()).unwrap();
+        sink.flush().unwrap();
+        sink.write(&reading()).unwrap();
+        drop(sink);
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line = LineProtocol::default().format(&reading());
+        assert_eq!(output, format!("{}\n{}\n", line, line));
+    }
+
+    #[test]
+    fn writes_a_batch_after_the_flush_interval() {
+        let buffer = SharedBuffer::default();
+        let mut sink = InfluxSink::writer(buffer.clone()).batch_size(100).flush_interval(Duration::from_millis(50)).open().unwrap();
+        sink.write(&reading()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while buffer.0.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "the batch was not written after the flush interval");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn posts_batches_to_the_write_api() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = serve_once(listener, "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        let mut sink = InfluxSink::http(&url, "my org", "home", Some("secret")).open().unwrap();
+        sink.write(&reading()).unwrap();
+        sink.flush().unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v2/write?org=my%20org&bucket=home&precision=ns HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains("Authorization: Token secret\r\n"), "{}", request);
+        assert!(request.ends_with(&LineProtocol::default().format(&reading())), "{}", request);
+    }
+
+    #[test]
+    fn reports_the_error_response_and_keeps_the_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = serve_once(listener, "HTTP/1.1 401 Unauthorized\r\nConnection: close\r\n\r\nunauthorized access");
+        let mut sink = InfluxSink::http(&url, "home", "environment", None).open().unwrap();
+        sink.write(&reading()).unwrap();
+        let error = sink.flush().unwrap_err();
+        assert!(error.to_string().contains("401 Unauthorized: unauthorized access"), "{}", error);
+        server.join().unwrap();
+
+        let listener = TcpListener::bind(url.trim_start_matches("http://")).unwrap();
+        let server = serve_once(listener, "HTTP/1.1 204 No Content\r\n\r\n");
+        sink.flush().unwrap();
+        assert!(server.join().unwrap().ends_with(&LineProtocol::default().format(&reading())));
+    }
+
+    #[test]
+    fn writing_does_not_wait_for_an_unresponsive_server() {
+        // The listener accepts connections into its backlog but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let mut sink = InfluxSink::http(&url, "home", "environment", None).batch_size(1).open().unwrap();
+        let start = Instant::now();
+        for _ in 0..QUEUE_SIZE {
+            sink.write(&reading()).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1), "writing took {:?}", start.elapsed());
+        // Closing the listener resets the connection, so dropping the sink does not wait for the timeout.
+        drop(listener);
+    }
+
+    #[test]
+    fn retries_with_a_doubling_backoff() {
+        let mut worker = Worker {
+            output: Output::Udp(UdpSocket::bind("127.0.0.1:0").unwrap()),
+            batch_size: 1,
+            flush_interval: Duration::from_secs(10),
+            pending: VecDeque::from(["aircontrol co2=650i".to_string()]),
+            oldest: Some(Instant::now()),
+            backoff: INITIAL_BACKOFF,
+            retry_at: None,
+        };
+        // The socket is not connected, so sending fails.
+        worker.write_batch();
+        assert!(worker.retry_at.is_some());
+        assert!(!worker.is_due());
+        assert_eq!(worker.backoff, INITIAL_BACKOFF * 2);
+        for _ in 0..10 {
+            worker.write_batch();
+        }
+        assert_eq!(worker.backoff, MAX_BACKOFF);
+        assert_eq!(worker.pending.len(), 1);
+    }
+
+    #[test]
+    fn parses_http_urls() {
+        assert_eq!(parse_url("http://localhost:8086").unwrap(), ("localhost:8086".to_string(), String::new()));
+        assert_eq!(parse_url("http://influx.local/proxy/").unwrap(), ("influx.local:80".to_string(), "/proxy".to_string()));
+        assert_eq!(parse_url("http://[::1]").unwrap(), ("[::1]:80".to_string(), String::new()));
+        assert_eq!(parse_url("https://influx.local").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(parse_url("http:///path").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn percent_encodes_query_values() {
+        assert_eq!(encode_query("my org/home&co"), "my%20org%2Fhome%26co");
+        assert_eq!(encode_query("a-b_c.d~"), "a-b_c.d~");
+    }
+}
@@ -0,0 +1,192 @@
+//! The item codes of the frames sent by the device.
+//!
+//! Every frame starts with an item code identifying the value it carries. `ItemCode` decodes the
+//! codes with a known meaning, names the other codes regularly sent by the device as
+//! `ItemCode::Status` and `ItemCode::Config` and passes all remaining codes through as
+//! `ItemCode::Unknown`. The values of the undecoded codes can be inspected with
+//! `AirControl::subscribe_raw`.
+//!
+//! Item codes are only created from the code byte with `ItemCode::from_code`, so every item code
+//! maps back to the byte it was created from.
+
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+const CO2: u8 = 0x50;
+const RAW_CO2: u8 = 0x71;
+const TEMPERATURE: u8 = 0x42;
+const HUMIDITY: u8 = 0x41;
+/// The codes of the status values which are regularly sent by the device.
+const STATUS: [u8; 5] = [0x43, 0x4F, 0x52, 0x56, 0x57];
+/// The codes of the configuration values which are regularly sent by the device.
+const CONFIG: [u8; 2] = [0x6D, 0x6E];
+
+/// The item code of a frame. It is serialized as its code byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(from = "u8", into = "u8"))]
+#[non_exhaustive]
+pub enum ItemCode {
+    /// `0x50`: The filtered CO2 concentration in ppm, as shown on the display.
+    Co2,
+    /// `0x71`: The unfiltered CO2 concentration in ppm.
+    RawCo2,
+    /// `0x42`: The ambient temperature in 1/16 Kelvin.
+    Temperature,
+    /// `0x41`: The relative humidity in 1/100 percent.
+    Humidity,
+    /// `0x43`, `0x4F`, `0x52`, `0x56` or `0x57`: A status value sent periodically by the device.
+    /// The encoding of the value is not documented, so it is not decoded.
+    Status(UndecodedCode),
+    /// `0x6D` or `0x6E`: A configuration value of the device, which does not change during normal
+    /// operation. The encoding of the value is not documented, so it is not decoded.
+    Config(UndecodedCode),
+    /// Any other item code, whose meaning is not documented.
+    Unknown(UndecodedCode),
+}
+
+/// The code byte of an item which is not decoded, only created by `ItemCode::from_code`, so it
+/// never carries the code of another variant.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UndecodedCode(u8);
+
+impl UndecodedCode {
+    /// Returns the code byte.
+    pub fn code(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Debug for UndecodedCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+impl ItemCode {
+    /// Returns the item code for the first byte of a frame.
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            CO2 => ItemCode::Co2,
+            RAW_CO2 => ItemCode::RawCo2,
+            TEMPERATURE => ItemCode::Temperature,
+            HUMIDITY => ItemCode::Humidity,
+            code if STATUS.contains(&code) => ItemCode::Status(UndecodedCode(code)),
+            code if CONFIG.contains(&code) => ItemCode::Config(UndecodedCode(code)),
+            code => ItemCode::Unknown(UndecodedCode(code)),
+        }
+    }
+
+    /// Returns the byte identifying this item in a frame.
+    pub fn code(self) -> u8 {
+        match self {
+            ItemCode::Co2 => CO2,
+            ItemCode::RawCo2 => RAW_CO2,
+            ItemCode::Temperature => TEMPERATURE,
+            ItemCode::Humidity => HUMIDITY,
+            ItemCode::Status(code) | ItemCode::Config(code) | ItemCode::Unknown(code) => code.code(),
+        }
+    }
+
+    /// Returns the unit of the decoded value, `None` for items which are not decoded.
+    pub fn unit(self) -> Option<&'static str> {
+        match self {
+            ItemCode::Co2 | ItemCode::RawCo2 => Some("ppm"),
+            ItemCode::Temperature => Some("°C"),
+            ItemCode::Humidity => Some("%"),
+            ItemCode::Status(_) | ItemCode::Config(_) | ItemCode::Unknown(_) => None,
+        }
+    }
+
+    /// Converts a raw value of this item into its unit, `None` for items which are not decoded.
+    pub fn decode(self, value: u16) -> Option<f32> {
+        match self {
+            ItemCode::Co2 | ItemCode::RawCo2 => Some(value as f32),
+            ItemCode::Temperature => Some(round_hundredths(value as f32 / 16.0 - 273.15)),
+            ItemCode::Humidity => Some(round_hundredths(value as f32 / 100.0)),
+            ItemCode::Status(_) | ItemCode::Config(_) | ItemCode::Unknown(_) => None,
+        }
+    }
+
+    /// Converts a value in the unit of this item back into its raw value, `None` for items which are not decoded.
+    ///
+    /// This is the inverse of `decode` for the values reported by the device.
+    pub fn encode(self, value: f32) -> Option<u16> {
+        match self {
+            ItemCode::Co2 | ItemCode::RawCo2 => Some(value.round() as u16),
+            ItemCode::Temperature => Some(((value + 273.15) * 16.0).round() as u16),
+            ItemCode::Humidity => Some((value * 100.0).round() as u16),
+            ItemCode::Status(_) | ItemCode::Config(_) | ItemCode::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u8> for ItemCode {
+    fn from(code: u8) -> Self {
+        ItemCode::from_code(code)
+    }
+}
+
+impl From<ItemCode> for u8 {
+    fn from(item: ItemCode) -> Self {
+        item.code()
+    }
+}
+
+/// The raw value of a frame, delivered for every item code including the undecoded ones.
+///
+/// # Fields
+/// - `item`: The item code of the frame.
+/// - `value`: The raw 16 bit value of the frame.
+/// - `received_at`: The timestamp when the frame was read from the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RawValue {
+    pub item: ItemCode,
+    pub value: u16,
+    pub received_at: DateTime<Utc>,
+}
+
+impl RawValue {
+    /// Converts the value into the unit of its item, `None` for items which are not decoded.
+    pub fn decoded(&self) -> Option<f32> {
+        self.item.decode(self.value)
+    }
+}
+
+/// Rounds a value to two decimal places.
+fn round_hundredths(value: f32) -> f32 {
+    (value * 100.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn item_codes_round_trip() {
+        for code in 0..=u8::MAX {
+            let item = ItemCode::from_code(code);
+            assert_eq!(item.code(), code);
+            assert_eq!(ItemCode::from_code(item.code()), item);
+            assert_eq!(u8::from(item), code);
+        }
+        assert_eq!(ItemCode::from_code(0x52), ItemCode::Status(UndecodedCode(0x52)));
+        assert_eq!(ItemCode::from_code(0x6d), ItemCode::Config(UndecodedCode(0x6d)));
+        assert_eq!(ItemCode::from_code(0x01), ItemCode::Unknown(UndecodedCode(0x01)));
+    }
+
+    #[test]
+    fn debug_shows_undecoded_codes_in_hex() {
+        assert_eq!(format!("{:?}", ItemCode::from_code(0x52)), "Status(0x52)");
+        assert_eq!(format!("{:?}", ItemCode::from_code(0x01)), "Unknown(0x01)");
+    }
+
+    #[test]
+    fn encode_inverts_decode() {
+        for (item, value) in [(ItemCode::Co2, 650), (ItemCode::RawCo2, 700), (ItemCode::Temperature, 4711), (ItemCode::Humidity, 4520)] {
+            assert_eq!(item.decode(value).and_then(|decoded| item.encode(decoded)), Some(value));
+        }
+        assert_eq!(ItemCode::from_code(0x52).decode(1), None);
+        assert_eq!(ItemCode::from_code(0x6d).encode(1.0), None);
+    }
+}
@@ -2,19 +2,74 @@
 //! The goal is to monitor environmental parameters such as CO2 levels, temperature, and humidity.
 //! It leverages the `hidapi` library for cross-plattform HID communication. The library provides a structured 
 //! and multithreaded approach to data acquisition and event handling.
+//!
+//! The device is accessed through the `Transport` trait, so `AirControl` can also be driven by a
+//! `ScriptedDevice` when no hardware is available, or replay a recording from a `ReplayDevice`.
 
+pub mod alarm;
+#[cfg(feature = "tokio")]
+pub mod async_api;
+pub mod builder;
+pub mod capture;
+pub mod channel;
+pub mod device;
+pub mod error;
+#[doc(hidden)]
+pub mod escape;
+#[cfg(feature = "prometheus")]
+pub mod exporter;
+pub mod frame;
+pub mod influx;
+pub mod item;
+pub mod measurement;
+mod monitor;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod reconnect;
+pub mod recording;
+pub mod replay;
+pub mod stats;
+#[cfg(feature = "sqlite")]
+pub mod store;
+pub mod subscription;
+pub mod transport;
 
-use hidapi::{HidApi, HidDevice};
+pub use alarm::{AirQuality, Alarm, AlarmConfig, AlarmEvent};
+#[cfg(feature = "tokio")]
+pub use async_api::{AsyncAirControl, DeviceDataStream};
+pub use builder::AirControlBuilder;
+pub use capture::{CaptureReader, CaptureWriter};
+pub use channel::{OverflowPolicy, Readings};
+pub use device::{list_devices, DeviceInfo};
+pub use error::{Error, Result};
+#[cfg(feature = "prometheus")]
+pub use exporter::{MetricsExporter, MetricsServer};
+pub use frame::{Frame, FrameEncoding, FrameError, LinkStats};
+pub use influx::{InfluxSink, LineProtocol};
+pub use item::{ItemCode, RawValue, UndecodedCode};
+pub use measurement::{Aggregator, Measurement, MeasurementKind};
+#[cfg(feature = "mqtt")]
+pub use mqtt::{MqttConfig, MqttPublisher};
+pub use reconnect::{ConnectionState, ReconnectPolicy};
+pub use recording::RecordingSink;
+pub use replay::{ReplayDevice, ReplaySpeed};
+pub use stats::{RollingWindow, Statistics, WindowStats};
+#[cfg(feature = "sqlite")]
+pub use store::Store;
+pub use subscription::Subscription;
+pub use transport::{ScriptedDevice, Transport};
+
+use alarm::AlarmCallback;
+use channel::Subscriber;
+use monitor::{Callback, ConnectionCallback, MeasurementCallback, Monitor, RawCallback};
 use chrono::{DateTime, Utc};
-use std::{thread, time, sync::{Arc, Mutex}};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{thread, sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError}};
+use std::sync::atomic::Ordering;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 const VENDOR_ID: u16 = 0x04d9;
 const PRODUCT_ID: u16 = 0xa052;
-const CO2_ADDRESS: u8 = 0x50;
-const TEMPERATURE_ADDRESS: u8 = 0x42;
-const HUMIDITY_ADDRESS: u8 = 0x41;
 
 /// Contains data of a single set of sensor readings collected from a AirControl device.
 ///
@@ -22,96 +77,204 @@ const HUMIDITY_ADDRESS: u8 = 0x41;
 /// - `time`: The timestamp when the data was read from the device.
 /// - `co2`: The CO2 concentration in parts per million (ppm).
 /// - `temperature`: The ambient temperature at the time of the reading, in degrees Celsius.
-/// - `humidity`: The relative humidity percentage at the time of the reading.
+/// - `humidity`: The relative humidity percentage at the time of the reading, `None` if the device does not report it.
+/// - `raw_co2`: The unfiltered CO2 concentration in ppm, `None` if it was not received yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceData {
     time: DateTime<Utc>,
     co2: u16,
     temperature: f32,
-    humidity: f32,
+    humidity: Option<f32>,
+    raw_co2: Option<u16>,
 }
 
-type Callback = Box<dyn Fn(DateTime<Utc>, u16, f32, f32) + Send>;
+impl DeviceData {
+    /// Creates a new set of sensor readings, e.g. to feed recorded readings into consumers.
+    pub fn new(time: DateTime<Utc>, co2: u16, temperature: f32, humidity: Option<f32>) -> Self {
+        DeviceData { time, co2, temperature, humidity, raw_co2: None }
+    }
+
+    /// Returns the readings with the given unfiltered CO2 concentration.
+    pub fn with_raw_co2(self, raw_co2: Option<u16>) -> Self {
+        DeviceData { raw_co2, ..self }
+    }
+
+    /// Returns the timestamp when the data was read from the device.
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    /// Returns the filtered CO2 concentration in parts per million (ppm), as shown on the display.
+    pub fn co2(&self) -> u16 {
+        self.co2
+    }
+
+    /// Returns the unfiltered CO2 concentration in parts per million (ppm), `None` if it was not received yet.
+    ///
+    /// The unfiltered value reacts faster to changes, but is noisier than `co2`.
+    pub fn raw_co2(&self) -> Option<u16> {
+        self.raw_co2
+    }
+
+    /// Returns the ambient temperature in degrees Celsius.
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    /// Returns the relative humidity in percent, `None` if the device does not report it.
+    pub fn humidity(&self) -> Option<f32> {
+        self.humidity
+    }
+
+    /// Returns whether both readings carry the same values, regardless of their timestamps.
+    pub(crate) fn same_values(&self, other: &DeviceData) -> bool {
+        self.co2 == other.co2 && self.raw_co2 == other.raw_co2 && self.temperature == other.temperature && self.humidity == other.humidity
+    }
+}
 
 /// Represents a struct for the AirControl coach and mini devices, allowing for monitoring of CO2 levels, temperature, and humidity.
 ///
 /// # Fields
-/// - `device`: A thread-safe reference to the HID device interface.
-/// - `callbacks`: A list of callback functions to be called with updated sensor data.
-/// - `running`: A flag indicating whether the monitoring loop is currently running.
+/// - `monitor`: The state shared with the monitoring thread, including the device and the callbacks.
+/// - `info`: The description of the opened device, `None` for custom transports.
 /// - `monitoring_thread`: The thread, which reads the values and sends them to the callback functions
 pub struct AirControl {
-    device: Arc<Mutex<HidDevice>>,
-    callbacks: Arc<Mutex<Vec<Callback>>>,
-    running: Arc<AtomicBool>,
+    monitor: Arc<Monitor>,
+    info: Option<DeviceInfo>,
     monitoring_thread: Option<JoinHandle<()>>,
 }
 
 /// Initializes a new instance of the AirControl interface.
 ///
-/// Attempts to create a HID API instance and open the specified device. On success, returns
-/// an `AirControl` object, otherwise returns an error string indicating the failure reason.
+/// Attempts to create a HID API instance and open the first attached device. On success, returns
+/// an `AirControl` object, otherwise returns an `Error` indicating the failure reason.
 ///
 /// # Errors
-/// Returns an error if the HID API instance cannot be created or the device cannot be opened.
+/// Returns `Error::Api` if the HID API instance cannot be created, `Error::DeviceNotFound` if no
+/// device is attached and `Error::Open` if the device cannot be opened.
 impl AirControl {
-    pub fn new() -> Result<Self, &'static str> {
-        let api = HidApi::new().map_err(|_| "Failed to create HID API instance")?;
-        let device = api.open(VENDOR_ID, PRODUCT_ID).map_err(|_| "Failed to open device")?;
+    pub fn new() -> Result<Self> {
+        Self::builder().open()
+    }
 
-        device.send_feature_report(&[0x00, 0x00]).expect("Failed to send feature report");
+    /// Returns a builder to configure the interface before the device is opened.
+    ///
+    /// ```no_run
+    /// # use aircontrol::AirControl;
+    /// # use std::time::Duration;
+    /// let air_control = AirControl::builder()
+    ///     .poll_interval(Duration::from_secs(60))
+    ///     .read_timeout(Duration::from_secs(5))
+    ///     .open();
+    /// ```
+    pub fn builder() -> AirControlBuilder {
+        AirControlBuilder::default()
+    }
 
-        let device = Arc::new(Mutex::new(device));
+    /// Initializes a new instance of the AirControl interface for the device with the given serial number.
+    ///
+    /// # Parameters
+    /// - `serial_number`: The serial number of the device, as listed by `list_devices`.
+    ///
+    /// # Errors
+    /// Returns `Error::DeviceNotFound` if no attached device has the serial number and
+    /// `Error::AmbiguousDevice` if several attached devices have it, e.g. because they report their
+    /// firmware version as serial number. Otherwise returns the same errors as `new`.
+    pub fn open_serial(serial_number: &str) -> Result<Self> {
+        Self::builder().serial_number(serial_number).open()
+    }
 
-        let callbacks = Arc::new(Mutex::new(Vec::new()));
-        let running = Arc::new(AtomicBool::new(true));
-        let monitoring_thread = None;
+    /// Initializes a new instance of the AirControl interface for the device with the given HID path.
+    ///
+    /// # Parameters
+    /// - `path`: The HID path of the device, as listed by `list_devices`.
+    ///
+    /// # Errors
+    /// Returns `Error::DeviceNotFound` if no attached device has the path, otherwise the same
+    /// errors as `new`.
+    pub fn open_path(path: &str) -> Result<Self> {
+        Self::builder().path(path).open()
+    }
+
+    /// Lists all attached AirControl devices.
+    ///
+    /// # Errors
+    /// Returns `Error::Api` if the HID API instance cannot be created.
+    pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+        device::list_devices()
+    }
 
-        Ok(  AirControl {
-            device,
-            callbacks,
-            running,
-            monitoring_thread,
-        })
+    /// Initializes a new instance of the AirControl interface on top of the given transport.
+    ///
+    /// Sends the initial feature report containing the decryption key to the device and returns an
+    /// `AirControl` object reading from it. Whether the device encrypts its reports is detected
+    /// automatically. This allows to use the interface with any `Transport`, e.g. a `ScriptedDevice`.
+    /// As the transport cannot be re-opened, the monitoring stops when it gets disconnected.
+    ///
+    /// # Parameters
+    /// - `device`: The transport used to talk to the device.
+    ///
+    /// # Errors
+    /// Returns `Error::FeatureReport` if the initial feature report cannot be sent.
+    pub fn with_transport<T: Transport + 'static>(device: T) -> Result<Self> {
+        Self::builder().with_transport(device)
+    }
+
+    /// Initializes a new instance of the AirControl interface on top of transports created by `connect`.
+    ///
+    /// `connect` is called once to create the initial transport and again for every reconnection
+    /// attempt after the transport got disconnected.
+    ///
+    /// # Parameters
+    /// - `connect`: Creates a transport used to talk to the device.
+    ///
+    /// # Errors
+    /// Returns the error of `connect` if the initial transport cannot be created and
+    /// `Error::FeatureReport` if the initial feature report cannot be sent.
+    pub fn with_connector<T, F>(connect: F) -> Result<Self>
+    where
+        T: Transport + 'static,
+        F: Fn() -> Result<T> + Send + Sync + 'static,
+    {
+        Self::builder().with_connector(connect)
+    }
+
+    /// Initializes a new instance of the AirControl interface replaying a recording.
+    ///
+    /// The recorded readings are delivered to the callbacks and channels with their original
+    /// timestamps, without being aggregated from single measurements again. The required
+    /// measurements thus do not apply, and no raw values or single measurements are delivered.
+    /// Every recorded reading is delivered, the poll interval does not coalesce them however fast
+    /// the replay runs. The monitoring stops once all readings were delivered.
+    ///
+    /// # Parameters
+    /// - `device`: The recording to replay.
+    pub fn replay(device: ReplayDevice) -> Self {
+        Self::builder().replay(device)
     }
 
     /// Starts the monitoring process in a separate thread.
     ///
     /// Spawns a new thread and saves them in 'monitoring_thread`. It continuously reads
     /// data from the device and invokes registered callbacks with the latest sensor readings. 
-    /// The loop runs until `stop_monitoring` is called.
-    ///
-    /// # Returns
-    /// A `JoinHandle` for the spawned thread, allowing the caller to manage the thread's lifecycle.
+    /// A panicking callback is reported on stderr and does not stop the monitoring.
+    /// If the device gets disconnected, it is re-opened according to the reconnect policy.
+    /// The loop runs until `stop_monitoring` is called or all reconnection attempts failed.
     pub fn start_monitoring(&mut self) {
-        let device = self.device.clone();
-        let running = self.running.clone();
-        let callbacks = self.callbacks.clone();
-        let monitoring_thread = thread::spawn(move || {
-            while running.load(Ordering::SeqCst) {
-                let device = device.lock().unwrap();
-                match  AirControl::read_data(&*device) {
-                    Ok(data) => {
-                        let cbs = callbacks.lock().unwrap();
-                        for cb in cbs.iter() {
-                            cb(data.time, data.co2, data.temperature, data.humidity);
-                        }
-                    }
-                    Err(error) => {
-                        eprintln!("Error reading data: {}", error);
-                        break;
-                    }
-                }
-                thread::sleep(time::Duration::from_millis(100));
-            }
-        });
-        self.monitoring_thread = Some(monitoring_thread);
+        if self.monitoring_thread.as_ref().is_some_and(|thread| !thread.is_finished()) {
+            return;
+        }
+        let monitor = self.monitor.clone();
+        monitor.running.store(true, Ordering::SeqCst);
+        self.monitoring_thread = Some(thread::spawn(move || monitor.run()));
     }
 
     /// Stops the monitoring process.
     ///
     /// Sets the `running` flag to `false`, which signals the monitoring thread to terminate and waits for the thread to finish.
     pub fn stop_monitoring(&mut self){
-        self.running.store(false, Ordering::SeqCst);
+        self.monitor.running.store(false, Ordering::SeqCst);
         if let Some(monitoring_thread) = self.monitoring_thread.take() {
             let _ = monitoring_thread.join();
         }
@@ -119,48 +282,311 @@ impl AirControl {
 
     /// Registers a new callback function to be invoked with sensor data updates.
     ///
+    /// Callbacks can be registered and removed while the monitoring is running, also from within a callback.
+    ///
     /// # Parameters
-    /// - `callback`: A `Callback` function that takes sensor readings as parameters.
-    pub fn register_callback(&self, callback: Callback) {
-        let mut cbs = self.callbacks.lock().unwrap();
-        cbs.push(callback);
+    /// - `callback`: A `Callback` function that takes the `DeviceData` of a reading as parameter.
+    ///
+    /// # Returns
+    /// The subscription, which removes the callback when it is dropped or cancelled.
+    pub fn register_callback(&self, callback: Callback) -> Subscription {
+        self.monitor.callbacks.add(callback)
     }
 
-    /// Reads sensor data from the device.
+    /// Subscribes to the sensor data updates through an unbounded channel.
     ///
-    /// Attempts to read CO2 levels, temperature, and humidity from the device. If successful, returns
-    /// a `DeviceData` struct containing the readings and the current timestamp. If any reading fails,
-    /// returns an error string describing the failure.
+    /// The channel is fed by the monitoring thread, which delivers every reading to all
+    /// callbacks and channels. It is closed when the monitoring stops.
+    pub fn subscribe(&self) -> mpsc::Receiver<DeviceData> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.monitor.subscribers).push(Subscriber::Unbounded(sender));
+        receiver
+    }
+
+    /// Subscribes to the sensor data updates through a bounded channel.
     ///
-    /// # Errors
-    /// Returns an error if the device cannot be read or if any sensor reading fails.
-    fn read_data(device: &HidDevice) -> Result<DeviceData, String> {
-        let mut buf = [0u8; 8];
-        let mut co2: Option<u16> = None;
-        let mut temperature: Option<f32> = None;
-        let mut humidity: Option<f32> = None;
-    
-        while co2.is_none() || temperature.is_none() || humidity.is_none() {
-            match device.read_timeout(&mut buf, 10000) {
-                Ok(_) => {
-                    let key = buf[0];
-                    let value = ((buf[1] as u16) << 8) | buf[2] as u16;
-    
-                    match key {
-                        CO2_ADDRESS => co2 = Some(value),
-                        TEMPERATURE_ADDRESS => temperature = Some(format!("{:.2}", value as f32 / 16.0 - 273.15).parse::<f32>().unwrap()),
-                        HUMIDITY_ADDRESS => humidity = Some(format!("{:.2}", value as f32 / 100.0).parse::<f32>().unwrap()),
-                        _ => {}
-                    }
-                },
-                Err(error) => return Err(format!("Could not read the device: {:?}", error)),
+    /// # Parameters
+    /// - `capacity`: The number of readings the channel can hold.
+    /// - `overflow`: Controls what happens with a reading when the channel is full.
+    pub fn subscribe_bounded(&self, capacity: usize, overflow: OverflowPolicy) -> mpsc::Receiver<DeviceData> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        lock(&self.monitor.subscribers).push(Subscriber::Bounded(sender, overflow));
+        receiver
+    }
+
+    /// Returns a blocking iterator over the sensor data updates.
+    ///
+    /// The iterator waits for the readings of the monitoring thread and ends when the monitoring stops.
+    pub fn readings(&self) -> Readings {
+        Readings::new(self.subscribe())
+    }
+
+    /// Records every sensor data update with the given sink.
+    ///
+    /// Errors while writing are reported on stderr and do not stop the monitoring.
+    ///
+    /// # Parameters
+    /// - `sink`: The sink the readings are appended to, e.g. `RecordingSink::csv("readings.csv").open()?`.
+    ///
+    /// # Returns
+    /// The subscription, which stops the recording and closes the sink when it is dropped or cancelled.
+    pub fn record(&self, mut sink: RecordingSink) -> Subscription {
+        self.register_callback(Box::new(move |data| {
+            if let Err(error) = sink.write(data) {
+                eprintln!("Error recording data: {}", error);
+            }
+        }))
+    }
+
+    /// Writes every sensor data update in the InfluxDB line protocol with the given sink.
+    ///
+    /// Errors while writing are reported on stderr and do not stop the monitoring.
+    ///
+    /// # Parameters
+    /// - `sink`: The sink the readings are written to, e.g. `InfluxSink::stdout().open()?`.
+    ///
+    /// # Returns
+    /// The subscription, which stops the writing and flushes the sink when it is dropped or cancelled.
+    pub fn record_influx(&self, mut sink: InfluxSink) -> Subscription {
+        self.register_callback(Box::new(move |data| {
+            if let Err(error) = sink.write(data) {
+                eprintln!("Error writing line protocol: {}", error);
+            }
+        }))
+    }
+
+    /// Stores every sensor data update in an SQLite database under the given device id.
+    ///
+    /// Errors while writing are reported on stderr and do not stop the monitoring.
+    ///
+    /// # Parameters
+    /// - `store`: The database, e.g. `Store::open("readings.db")?`.
+    /// - `device`: The id the readings are stored under, e.g. the serial number or the name of the room.
+    ///
+    /// # Returns
+    /// The subscription, which stops the storing and closes the database when it is dropped or cancelled.
+    #[cfg(feature = "sqlite")]
+    pub fn record_store(&self, store: Store, device: &str) -> Subscription {
+        let device = device.to_string();
+        self.register_callback(Box::new(move |data| {
+            if let Err(error) = store.insert(&device, data) {
+                eprintln!("Error storing data: {}", error);
             }
+        }))
+    }
+
+    /// Registers a new callback function to be invoked with every single measurement.
+    ///
+    /// Measurements are delivered as soon as their frame was read, before they are combined into `DeviceData`.
+    ///
+    /// # Parameters
+    /// - `callback`: A function that takes the `Measurement` as parameter.
+    ///
+    /// # Returns
+    /// The subscription, which removes the callback when it is dropped or cancelled.
+    pub fn register_measurement_callback(&self, callback: MeasurementCallback) -> Subscription {
+        self.monitor.measurement_callbacks.add(callback)
+    }
+
+    /// Subscribes to the single measurements through an unbounded channel.
+    ///
+    /// The channel is closed when the monitoring stops.
+    pub fn subscribe_measurements(&self) -> mpsc::Receiver<Measurement> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.monitor.measurement_subscribers).push(sender);
+        receiver
+    }
+
+    /// Registers a new callback function to be invoked with the raw value of every valid frame.
+    ///
+    /// Unlike measurements, raw values are delivered for all item codes, including the ones which are not decoded.
+    ///
+    /// # Parameters
+    /// - `callback`: A function that takes the `RawValue` as parameter.
+    ///
+    /// # Returns
+    /// The subscription, which removes the callback when it is dropped or cancelled.
+    pub fn register_raw_callback(&self, callback: RawCallback) -> Subscription {
+        self.monitor.raw_callbacks.add(callback)
+    }
+
+    /// Subscribes to the raw values of all valid frames through an unbounded channel.
+    ///
+    /// The channel is closed when the monitoring stops.
+    pub fn subscribe_raw(&self) -> mpsc::Receiver<RawValue> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.monitor.raw_subscribers).push(sender);
+        receiver
+    }
+
+    /// Registers a new callback function to be invoked when the air quality level changes.
+    ///
+    /// Every callback tracks its own `Alarm` over the sensor data updates, so callbacks with
+    /// different thresholds can be registered side by side.
+    ///
+    /// # Parameters
+    /// - `config`: The thresholds of the alarm.
+    /// - `callback`: A function that takes the `AlarmEvent` as parameter.
+    ///
+    /// # Returns
+    /// The subscription, which removes the callback when it is dropped or cancelled.
+    pub fn register_alarm_callback(&self, config: AlarmConfig, callback: AlarmCallback) -> Subscription {
+        self.monitor.alarm_callbacks.add((Alarm::new(config), callback))
+    }
+
+    /// Subscribes to the changes of the air quality level through an unbounded channel.
+    ///
+    /// The channel is closed when the monitoring stops.
+    ///
+    /// # Parameters
+    /// - `config`: The thresholds of the alarm.
+    pub fn subscribe_alarms(&self, config: AlarmConfig) -> mpsc::Receiver<AlarmEvent> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.monitor.alarm_subscribers).push((Alarm::new(config), sender));
+        receiver
+    }
+
+    /// Starts collecting rolling statistics over windows of the given duration.
+    ///
+    /// Windows are maintained for all kinds of measurements and fed with every sensor data update,
+    /// starting with the next one. Adding a duration which is already tracked has no effect.
+    ///
+    /// # Parameters
+    /// - `window`: The duration of the windows, e.g. one hour.
+    pub fn track_statistics(&self, window: Duration) {
+        lock(&self.monitor.statistics).add_window(window);
+    }
+
+    /// Returns the summary of a rolling window ending now.
+    ///
+    /// The latest value holds until now, as the device only reports changed values. When replaying
+    /// a recording, the window ends at the latest replayed reading instead.
+    ///
+    /// # Parameters
+    /// - `kind`: The kind of measurement.
+    /// - `window`: The duration of the window, as passed to `track_statistics`.
+    ///
+    /// # Returns
+    /// The summary, `None` if the window is not tracked or contains no values yet.
+    pub fn statistics(&self, kind: MeasurementKind, window: Duration) -> Option<WindowStats> {
+        self.current_statistics().window(kind, window)?.summary()
+    }
+
+    /// Runs `f` on a rolling window ending now, e.g. to query percentiles or the time above a threshold.
+    ///
+    /// The window ends like the one of `statistics`.
+    /// The monitoring thread waits for `f` before it delivers the next update, so `f` should return quickly.
+    ///
+    /// # Returns
+    /// The result of `f`, `None` if the window is not tracked.
+    pub fn with_statistics<R>(&self, kind: MeasurementKind, window: Duration, f: impl FnOnce(&RollingWindow) -> R) -> Option<R> {
+        self.current_statistics().window(kind, window).map(f)
+    }
+
+    /// Locks the statistics and moves the end of their windows to now, unless a recording is replayed.
+    fn current_statistics(&self) -> MutexGuard<'_, Statistics> {
+        let mut statistics = lock(&self.monitor.statistics);
+        if self.monitor.replay.is_none() {
+            statistics.advance(Utc::now());
         }
-        Ok(DeviceData {
-            time: Utc::now(),
-            co2: co2.unwrap(),
-            temperature: temperature.unwrap(),
-            humidity: humidity.unwrap(),
-        })
+        statistics
+    }
+
+    /// Sets the kinds of measurements the device reports.
+    ///
+    /// A `DeviceData` is only emitted once all of them were received, so kinds which are never
+    /// reported by the device, e.g. the humidity of some models, must not be listed. CO2 and
+    /// temperature are always required. By default, CO2, temperature and humidity are required.
+    /// The required measurements do not apply to a replay, which delivers the recorded readings.
+    pub fn set_required_measurements(&self, required: &[MeasurementKind]) {
+        *lock(&self.monitor.aggregator) = Aggregator::new(required);
+    }
+
+    /// Registers a new callback function to be invoked whenever the connection state changes.
+    ///
+    /// # Parameters
+    /// - `callback`: A function that takes the new `ConnectionState` as parameter.
+    ///
+    /// # Returns
+    /// The subscription, which removes the callback when it is dropped or cancelled.
+    pub fn register_connection_callback(&self, callback: ConnectionCallback) -> Subscription {
+        self.monitor.connection_callbacks.add(callback)
+    }
+
+    /// Sets the policy used to re-open the device after a disconnection.
+    pub fn set_reconnect_policy(&self, policy: ReconnectPolicy) {
+        *lock(&self.monitor.policy) = policy;
+    }
+
+    /// Sets the minimum interval between two sensor data updates, see `AirControlBuilder::poll_interval`.
+    ///
+    /// The interval can be changed while the monitoring is running. It only limits how often changed
+    /// sensor data is delivered and does not cause any updates while the readings are stable.
+    pub fn set_poll_interval(&self, poll_interval: Duration) {
+        *lock(&self.monitor.poll_interval) = poll_interval;
+    }
+
+    /// Returns the minimum interval between two sensor data updates.
+    pub fn poll_interval(&self) -> Duration {
+        *lock(&self.monitor.poll_interval)
     }
+
+    /// Returns the current state of the connection to the device.
+    pub fn connection_state(&self) -> ConnectionState {
+        *lock(&self.monitor.state)
+    }
+
+    /// Returns the description of the opened device, `None` if the interface was created with a custom transport.
+    pub fn device_info(&self) -> Option<&DeviceInfo> {
+        self.info.as_ref()
+    }
+
+    /// Returns the name identifying the device in exported metrics and published topics: its serial
+    /// number, its path if it has none or an empty one, or `unknown` for a custom transport.
+    #[cfg(any(feature = "prometheus", feature = "mqtt"))]
+    pub(crate) fn device_name(&self) -> String {
+        self.info
+            .as_ref()
+            .map(|info| info.serial_number.clone().filter(|serial| !serial.is_empty()).unwrap_or_else(|| info.path.clone()))
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// Starts recording every report read from the device into `capture`, replacing a running capture.
+    ///
+    /// The reports are recorded exactly as they were read, before they are decrypted or validated.
+    /// The key the device was initialized with is written into the header of a new capture.
+    /// If the capture cannot be written, the error is reported on stderr and the capture is stopped.
+    ///
+    /// # Parameters
+    /// - `capture`: The capture the reports are appended to, e.g. `CaptureWriter::create("device.acap")?`.
+    pub fn start_capture(&self, mut capture: CaptureWriter) {
+        capture.set_key(self.monitor.decoder.key());
+        *lock(&self.monitor.capture) = Some(capture);
+    }
+
+    /// Stops the running capture.
+    ///
+    /// # Returns
+    /// The stopped capture, `None` if no capture was running.
+    pub fn stop_capture(&self) -> Option<CaptureWriter> {
+        lock(&self.monitor.capture).take()
+    }
+
+    /// Returns a snapshot of the link quality counters.
+    ///
+    /// The counters cover all reports read since the `AirControl` was created, including the ones
+    /// rejected because of a wrong length, checksum or terminator.
+    pub fn link_stats(&self) -> LinkStats {
+        self.monitor.link.snapshot()
+    }
+
+    /// Returns the detected encoding of the reports, `None` if no valid report was received yet.
+    pub fn frame_encoding(&self) -> Option<FrameEncoding> {
+        self.monitor.decoder.encoding()
+    }
+}
+
+/// Locks a mutex, recovering the data if another thread panicked while holding the lock.
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
@@ -1,14 +1,676 @@
-use aircontrol::AirControl;
-use std::thread;
+//! Command-line tool to inspect AirControl devices without writing code.
+//!
+//! Run `aircontrol help` for the list of commands and options.
+
+use aircontrol::{AirControl, CaptureReader, CaptureWriter, DeviceData, DeviceInfo, Error, MeasurementKind, RawValue};
+use aircontrol::escape::{csv_field, json_string};
+use std::error::Error as _;
+use std::fmt;
+use std::io::{self, Write};
+use std::process::ExitCode;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::time::Duration;
 
-fn main() {
-    let mut monitor = AirControl::new().expect("Failed to initialize the AirControl Interface");
-    monitor.register_callback(Box::new(|time, co2, temperature, humidity| {
-        let formatted_time = time.format("%Y-%m-%d %H:%M:%S").to_string();
-        println!("Time: {}, CO2: {}ppm, Temperature: {:.1}C, Humidity: {:.0}%", formatted_time, co2, temperature, humidity);
-    }));
-    monitor.start_monitoring();
-    thread::sleep(Duration::from_secs(10));
-    monitor.stop_monitoring();
-}
\ No newline at end of file
+const USAGE: &str = "\
+Usage: aircontrol <COMMAND> [OPTIONS]
+
+Commands:
+  list                 List the attached devices
+  read                 Print a single reading and exit
+  watch                Print readings as they change, until interrupted
+  log                  Like watch, but prints CSV by default
+  capture <FILE>       Record the raw reports of the device into a capture file, until interrupted
+  decode <FILE>        Decode the reports of a capture file
+  history <FILE>       Print the readings stored in a database, see --store
+  help                 Print this help
+  version              Print the version
+
+Options:
+  --device <SERIAL>    Use the device with the given serial number
+  --path <PATH>        Use the device with the given HID path
+  --interval <TIME>    Minimum time between two changed readings, e.g. 500ms, 5s or 1m;
+                       unchanged readings are never printed [default: 0s]
+  --timeout <TIME>     Time to wait for a reading before giving up [default: 30s]
+  --once               Stop after the first reading, the same as --count 1
+  --count <N>          Stop after N readings, or N reports when capturing
+  --format <FORMAT>    Output format: text, csv or json [default: text, csv for log]
+  --unit <UNIT>        Temperature unit: celsius, fahrenheit or kelvin [default: celsius]
+  --no-humidity        Do not wait for humidity, for devices without a humidity sensor
+  --store <FILE>       Also store the readings in an SQLite database, under the serial number
+  --since <TIME>       Print the history of the given time before now, e.g. 7d [default: 24h]
+  --bucket <TIME>      Print the history downsampled into buckets of the given width
+
+The --store option and the history command require the sqlite feature. The history
+command selects the device id in the database with --device.
+
+Exit codes:
+  0  Success
+  1  Writing the output or accessing a file failed
+  2  Invalid command line
+  3  No matching device is attached
+  4  The device could not be opened or got disconnected
+  5  No reading was received within the timeout
+";
+
+/// The time the monitoring thread waits for a single report, which bounds the time needed to stop it.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// The time to wait for a reading if no `--timeout` is given.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How far back the history reaches if no `--since` is given.
+const DEFAULT_HISTORY: Duration = Duration::from_secs(24 * 3600);
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match parse(&args).and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        // The consumer of the output went away, e.g. `aircontrol watch | head`.
+        Err(CliError::Output(error)) if error.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("aircontrol: {}", error);
+            if let CliError::Usage(_) = error {
+                eprintln!("Run `aircontrol help` for usage.");
+            }
+            ExitCode::from(error.exit_code())
+        }
+    }
+}
+
+/// The subcommands of the tool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    List,
+    Read,
+    Watch,
+    Log,
+    Capture,
+    Decode,
+    History,
+    Help,
+    Version,
+}
+
+/// The output formats of the readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Csv,
+    Json,
+}
+
+/// The unit the temperature is printed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    fn convert(self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+            TemperatureUnit::Kelvin => celsius + 273.15,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+            TemperatureUnit::Kelvin => "K",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "celsius",
+            TemperatureUnit::Fahrenheit => "fahrenheit",
+            TemperatureUnit::Kelvin => "kelvin",
+        }
+    }
+}
+
+/// The parsed command line.
+///
+/// # Fields
+/// - `command`: The subcommand to run.
+/// - `file`: The capture file of the `capture` and `decode` commands.
+/// - `serial_number`: The serial number of the device to use, `None` for any device.
+/// - `path`: The HID path of the device to use, `None` for any device.
+/// - `interval`: The minimum time between two readings.
+/// - `timeout`: The time to wait for a reading.
+/// - `count`: The number of readings after which the tool exits, `None` to run until interrupted.
+/// - `format`: The output format of the readings.
+/// - `unit`: The unit the temperature is printed in.
+/// - `humidity`: Whether readings wait for the humidity.
+/// - `store`: The database the readings are stored in, `None` to store nothing.
+/// - `since`: How far back the history reaches.
+/// - `bucket`: The width of the buckets of the history, `None` to print every reading.
+#[derive(Debug, Clone)]
+struct Options {
+    command: Command,
+    file: Option<String>,
+    serial_number: Option<String>,
+    path: Option<String>,
+    interval: Duration,
+    timeout: Duration,
+    count: Option<usize>,
+    format: Format,
+    unit: TemperatureUnit,
+    humidity: bool,
+    store: Option<String>,
+    since: Duration,
+    bucket: Option<Duration>,
+}
+
+/// The errors reported by the tool, each with its own exit code.
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Device(Error),
+    Disconnected,
+    Timeout(Duration),
+    Output(io::Error),
+    File(String, io::Error),
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Output(_) | CliError::File(..) => 1,
+            CliError::Usage(_) => 2,
+            CliError::Device(Error::DeviceNotFound) => 3,
+            CliError::Device(_) | CliError::Disconnected => 4,
+            CliError::Timeout(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}", message),
+            CliError::Device(error) => {
+                write!(f, "{}", error)?;
+                let mut source = error.source();
+                while let Some(error) = source {
+                    write!(f, ": {}", error)?;
+                    source = error.source();
+                }
+                Ok(())
+            }
+            CliError::Disconnected => write!(f, "The device got disconnected"),
+            CliError::Timeout(timeout) => write!(f, "No reading received within {}", format_duration(*timeout)),
+            CliError::Output(error) => write!(f, "Could not write the output: {}", error),
+            CliError::File(path, error) => write!(f, "Could not access `{}`: {}", path, error),
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Output(error)
+    }
+}
+
+fn parse(args: &[String]) -> Result<Options, CliError> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        Some("list") => Command::List,
+        Some("read") => Command::Read,
+        Some("watch") => Command::Watch,
+        Some("log") => Command::Log,
+        Some("capture") => Command::Capture,
+        Some("decode") => Command::Decode,
+        Some("history") => Command::History,
+        Some("help" | "--help" | "-h") | None => Command::Help,
+        Some("version" | "--version" | "-V") => Command::Version,
+        Some(other) => return Err(CliError::Usage(format!("Unknown command `{}`", other))),
+    };
+    let mut options = Options {
+        command,
+        file: None,
+        serial_number: None,
+        path: None,
+        interval: Duration::ZERO,
+        timeout: DEFAULT_TIMEOUT,
+        count: (command == Command::Read).then_some(1),
+        format: if command == Command::Log { Format::Csv } else { Format::Text },
+        unit: TemperatureUnit::Celsius,
+        humidity: true,
+        store: None,
+        since: DEFAULT_HISTORY,
+        bucket: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| CliError::Usage(format!("Missing value for `{}`", arg)));
+        match arg.as_str() {
+            "--device" => options.serial_number = Some(value()?.clone()),
+            "--path" => options.path = Some(value()?.clone()),
+            "--interval" => options.interval = parse_duration(value()?)?,
+            "--timeout" => options.timeout = parse_duration(value()?)?,
+            "--once" => options.count = Some(1),
+            "--count" => {
+                let count = value()?;
+                options.count = match count.parse() {
+                    Ok(0) | Err(_) => return Err(CliError::Usage(format!("Invalid count `{}`", count))),
+                    Ok(count) => Some(count),
+                };
+            }
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "text" => Format::Text,
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    other => return Err(CliError::Usage(format!("Unknown format `{}`", other))),
+                };
+            }
+            "--unit" => {
+                options.unit = match value()?.as_str() {
+                    "c" | "celsius" => TemperatureUnit::Celsius,
+                    "f" | "fahrenheit" => TemperatureUnit::Fahrenheit,
+                    "k" | "kelvin" => TemperatureUnit::Kelvin,
+                    other => return Err(CliError::Usage(format!("Unknown temperature unit `{}`", other))),
+                };
+            }
+            "--no-humidity" => options.humidity = false,
+            "--store" => options.store = Some(value()?.clone()),
+            "--since" => options.since = parse_duration(value()?)?,
+            "--bucket" => {
+                let bucket = value()?;
+                options.bucket = match parse_duration(bucket)? {
+                    Duration::ZERO => return Err(CliError::Usage(format!("Invalid bucket width `{}`", bucket))),
+                    width => Some(width),
+                };
+            }
+            "--help" | "-h" => options.command = Command::Help,
+            file if !file.starts_with('-') && options.file.is_none() && matches!(command, Command::Capture | Command::Decode | Command::History) => {
+                options.file = Some(file.to_string());
+            }
+            other => return Err(CliError::Usage(format!("Unknown option `{}`", other))),
+        }
+    }
+    if matches!(options.command, Command::Capture | Command::Decode) && options.file.is_none() {
+        return Err(CliError::Usage("Missing the capture file".to_string()));
+    }
+    if options.command == Command::History && options.file.is_none() {
+        return Err(CliError::Usage("Missing the database file".to_string()));
+    }
+    if options.serial_number.is_some() && options.path.is_some() {
+        return Err(CliError::Usage("`--device` and `--path` cannot be used together".to_string()));
+    }
+    Ok(options)
+}
+
+/// Parses a duration like `500ms`, `5s`, `2m`, `1h` or `7d`. A plain number is taken as seconds.
+fn parse_duration(value: &str) -> Result<Duration, CliError> {
+    let invalid = || CliError::Usage(format!("Invalid duration `{}`", value));
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match suffix {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "d" => number * 86400.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+fn run(options: Options) -> Result<(), CliError> {
+    let mut out = io::stdout().lock();
+    match options.command {
+        Command::Help => write!(out, "{}", USAGE)?,
+        Command::Version => writeln!(out, "aircontrol {}", env!("CARGO_PKG_VERSION"))?,
+        Command::List => list(&mut out, &options)?,
+        Command::Read | Command::Watch | Command::Log => readings(&mut out, &options)?,
+        Command::Capture => capture(&mut out, &options)?,
+        Command::Decode => decode(&mut out, &options)?,
+        Command::History => history(&mut out, &options)?,
+    }
+    Ok(())
+}
+
+fn list(out: &mut impl Write, options: &Options) -> Result<(), CliError> {
+    let devices = AirControl::list_devices().map_err(CliError::Device)?;
+    match options.format {
+        Format::Text => {
+            for device in &devices {
+                writeln!(
+                    out,
+                    "{}  serial: {}  {} {}",
+                    device.path,
+                    device.serial_number.as_deref().unwrap_or("-"),
+                    device.manufacturer.as_deref().unwrap_or(""),
+                    device.product.as_deref().unwrap_or(""),
+                )?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "path,serial_number,manufacturer,product")?;
+            for device in &devices {
+                let fields = [Some(device.path.as_str()), device.serial_number.as_deref(), device.manufacturer.as_deref(), device.product.as_deref()];
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field.unwrap_or(""))).collect();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        Format::Json => {
+            for device in &devices {
+                writeln!(out, "{}", device_json(device))?;
+            }
+        }
+    }
+    if devices.is_empty() {
+        return Err(CliError::Device(Error::DeviceNotFound));
+    }
+    Ok(())
+}
+
+fn open_device(options: &Options) -> Result<AirControl, CliError> {
+    let mut builder = AirControl::builder().poll_interval(options.interval).read_timeout(READ_TIMEOUT);
+    if let Some(serial_number) = &options.serial_number {
+        builder = builder.serial_number(serial_number);
+    }
+    if let Some(path) = &options.path {
+        builder = builder.path(path);
+    }
+    if !options.humidity {
+        builder = builder.required_measurements(&[MeasurementKind::Co2, MeasurementKind::Temperature]);
+    }
+    builder.open().map_err(CliError::Device)
+}
+
+fn readings(out: &mut impl Write, options: &Options) -> Result<(), CliError> {
+    let mut air_control = open_device(options)?;
+    if let Some(file) = &options.store {
+        let device = air_control.device_info().map_or(String::new(), |info| info.serial_number.clone().unwrap_or_else(|| info.path.clone()));
+        store_readings(&air_control, file, &device)?;
+    }
+    let receiver = air_control.subscribe();
+    air_control.start_monitoring();
+
+    if options.format == Format::Csv {
+        writeln!(out, "{}", readings_header(options))?;
+    }
+    let result = print_readings(out, options, &receiver);
+    air_control.stop_monitoring();
+    result
+}
+
+fn print_readings(out: &mut impl Write, options: &Options, receiver: &Receiver<DeviceData>) -> Result<(), CliError> {
+    let mut printed = 0;
+    while options.count.is_none_or(|count| printed < count) {
+        // Watching waits for the device as long as it is connected, reading once gives up after the timeout.
+        let data = match receiver.recv_timeout(options.timeout) {
+            Ok(data) => data,
+            Err(RecvTimeoutError::Timeout) if options.count.is_none() => continue,
+            Err(RecvTimeoutError::Timeout) => return Err(CliError::Timeout(options.timeout)),
+            Err(RecvTimeoutError::Disconnected) => return Err(CliError::Disconnected),
+        };
+        writeln!(out, "{}", format_data(&data, options))?;
+        out.flush()?;
+        printed += 1;
+    }
+    Ok(())
+}
+
+fn capture(out: &mut impl Write, options: &Options) -> Result<(), CliError> {
+    let file = options.file.as_deref().unwrap_or_default();
+    let mut air_control = open_device(options)?;
+    air_control.start_capture(CaptureWriter::create(file).map_err(|error| CliError::File(file.to_string(), error))?);
+    let receiver = air_control.subscribe_raw();
+    air_control.start_monitoring();
+
+    let result = print_raw_values(out, options, &receiver);
+    air_control.stop_monitoring();
+    result
+}
+
+fn print_raw_values(out: &mut impl Write, options: &Options, receiver: &Receiver<RawValue>) -> Result<(), CliError> {
+    let mut printed = 0;
+    while options.count.is_none_or(|count| printed < count) {
+        let raw = match receiver.recv_timeout(options.timeout) {
+            Ok(raw) => raw,
+            Err(RecvTimeoutError::Timeout) if options.count.is_none() => continue,
+            Err(RecvTimeoutError::Timeout) => return Err(CliError::Timeout(options.timeout)),
+            Err(RecvTimeoutError::Disconnected) => return Err(CliError::Disconnected),
+        };
+        writeln!(out, "{}", format_raw(&raw))?;
+        out.flush()?;
+        printed += 1;
+    }
+    Ok(())
+}
+
+fn decode(out: &mut impl Write, options: &Options) -> Result<(), CliError> {
+    let file = options.file.as_deref().unwrap_or_default();
+    let file_error = |error| CliError::File(file.to_string(), error);
+    let mut reports = CaptureReader::open(file).map_err(file_error)?.decode();
+    for report in &mut reports {
+        let report = report.map_err(file_error)?;
+        let bytes: Vec<String> = report.report.iter().map(|byte| format!("{:02x}", byte)).collect();
+        match (report.raw_value(), &report.frame) {
+            (Some(raw), _) => writeln!(out, "{}  [{}]", format_raw(&raw), bytes.join(" "))?,
+            (None, Err(error)) => writeln!(out, "{}  rejected: {}  [{}]", format_time(&report.time), error, bytes.join(" "))?,
+            (None, Ok(_)) => {}
+        }
+    }
+    let stats = reports.link_stats();
+    let encoding = reports.frame_encoding().map_or("unknown".to_string(), |encoding| format!("{:?}", encoding).to_lowercase());
+    writeln!(
+        out,
+        "{} reports, {} accepted, {} rejected ({} length, {} checksum, {} terminator), encoding: {}",
+        stats.frames_received,
+        stats.frames_accepted,
+        stats.frames_rejected(),
+        stats.length_errors,
+        stats.checksum_errors,
+        stats.terminator_errors,
+        encoding,
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn store_readings(air_control: &AirControl, file: &str, device: &str) -> Result<(), CliError> {
+    let store = aircontrol::Store::open(file).map_err(|error| database_error(file, error))?;
+    air_control.record_store(store, device).detach();
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn store_readings(_air_control: &AirControl, _file: &str, _device: &str) -> Result<(), CliError> {
+    Err(CliError::Usage("`--store` requires the sqlite feature".to_string()))
+}
+
+#[cfg(feature = "sqlite")]
+fn history(out: &mut impl Write, options: &Options) -> Result<(), CliError> {
+    let file = options.file.as_deref().unwrap_or_default();
+    // Opening a database creates it, which is not wanted for a misspelled name.
+    if !std::path::Path::new(file).exists() {
+        return Err(CliError::File(file.to_string(), io::ErrorKind::NotFound.into()));
+    }
+    let store = aircontrol::Store::open(file).map_err(|error| database_error(file, error))?;
+    let device = match &options.serial_number {
+        Some(device) => device.clone(),
+        None => match store.devices().map_err(|error| database_error(file, error))?.as_slice() {
+            [] => return Ok(()),
+            [device] => device.clone(),
+            devices => {
+                return Err(CliError::Usage(format!("The database contains several devices, select one with --device: {}", devices.join(", "))));
+            }
+        },
+    };
+    let to = chrono::Utc::now();
+    let from = chrono::Duration::from_std(options.since).ok().and_then(|since| to.checked_sub_signed(since)).unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC);
+
+    match options.bucket {
+        None => {
+            let readings = store.range(&device, from, to).map_err(|error| database_error(file, error))?;
+            if options.format == Format::Csv {
+                writeln!(out, "{}", readings_header(options))?;
+            }
+            for data in &readings {
+                writeln!(out, "{}", format_data(data, options))?;
+            }
+        }
+        Some(width) => {
+            let buckets = store.buckets(&device, from, to, width).map_err(|error| database_error(file, error))?;
+            if options.format == Format::Csv {
+                writeln!(
+                    out,
+                    "start,readings,co2_mean_ppm,co2_min_ppm,co2_max_ppm,temperature_mean_{0},temperature_min_{0},temperature_max_{0},humidity_mean_percent",
+                    options.unit.name(),
+                )?;
+            }
+            for bucket in &buckets {
+                writeln!(out, "{}", format_bucket(bucket, options))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn history(_out: &mut impl Write, _options: &Options) -> Result<(), CliError> {
+    Err(CliError::Usage("`history` requires the sqlite feature".to_string()))
+}
+
+#[cfg(feature = "sqlite")]
+fn database_error(file: &str, error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> CliError {
+    CliError::File(file.to_string(), io::Error::other(error))
+}
+
+#[cfg(feature = "sqlite")]
+fn format_bucket(bucket: &aircontrol::store::Bucket, options: &Options) -> String {
+    let unit = options.unit;
+    let temperature = &bucket.temperature;
+    match options.format {
+        Format::Text => {
+            let humidity = bucket.humidity.map_or("-".to_string(), |humidity| format!("{:.0} %", humidity.mean));
+            format!(
+                "{}  CO2: {:.0} ppm ({:.0}-{:.0})  Temperature: {:.1} {}  Humidity: {}  Readings: {}",
+                bucket.start.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+                bucket.co2.mean,
+                bucket.co2.min,
+                bucket.co2.max,
+                unit.convert(temperature.mean),
+                unit.symbol(),
+                humidity,
+                bucket.count,
+            )
+        }
+        Format::Csv => format!(
+            "{},{},{:.1},{:.0},{:.0},{:.2},{:.2},{:.2},{}",
+            bucket.start.to_rfc3339(),
+            bucket.count,
+            bucket.co2.mean,
+            bucket.co2.min,
+            bucket.co2.max,
+            unit.convert(temperature.mean),
+            unit.convert(temperature.min),
+            unit.convert(temperature.max),
+            bucket.humidity.map_or(String::new(), |humidity| format!("{:.2}", humidity.mean)),
+        ),
+        Format::Json => format!(
+            concat!(
+                "{{\"start\":\"{}\",\"readings\":{},\"co2\":{{\"mean\":{:.1},\"min\":{:.0},\"max\":{:.0}}},",
+                "\"temperature\":{{\"mean\":{:.2},\"min\":{:.2},\"max\":{:.2}}},\"unit\":\"{}\",\"humidity\":{}}}"
+            ),
+            bucket.start.to_rfc3339(),
+            bucket.count,
+            bucket.co2.mean,
+            bucket.co2.min,
+            bucket.co2.max,
+            unit.convert(temperature.mean),
+            unit.convert(temperature.min),
+            unit.convert(temperature.max),
+            unit.name(),
+            bucket.humidity.map_or("null".to_string(), |humidity| {
+                format!("{{\"mean\":{:.2},\"min\":{:.2},\"max\":{:.2}}}", humidity.mean, humidity.min, humidity.max)
+            }),
+        ),
+    }
+}
+
+fn readings_header(options: &Options) -> String {
+    format!("time,co2_ppm,temperature_{},humidity_percent", options.unit.name())
+}
+
+fn format_time(time: &chrono::DateTime<chrono::Utc>) -> String {
+    time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+fn format_raw(raw: &RawValue) -> String {
+    let decoded = match (raw.decoded(), raw.item.unit()) {
+        (Some(value), Some(unit)) => format!("{} {}", value, unit),
+        _ => "-".to_string(),
+    };
+    format!("{}  {:<16} {:#06x}  {}", format_time(&raw.received_at), format!("{:?}", raw.item), raw.value, decoded)
+}
+
+fn format_data(data: &DeviceData, options: &Options) -> String {
+    let temperature = options.unit.convert(data.temperature());
+    match options.format {
+        Format::Text => {
+            let humidity = data.humidity().map_or("-".to_string(), |humidity| format!("{:.0} %", humidity));
+            format!(
+                "{}  CO2: {} ppm  Temperature: {:.1} {}  Humidity: {}",
+                data.time().with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+                data.co2(),
+                temperature,
+                options.unit.symbol(),
+                humidity,
+            )
+        }
+        Format::Csv => format!(
+            "{},{},{:.2},{}",
+            data.time().to_rfc3339(),
+            data.co2(),
+            temperature,
+            data.humidity().map_or(String::new(), |humidity| format!("{:.2}", humidity)),
+        ),
+        Format::Json => format!(
+            "{{\"time\":\"{}\",\"co2\":{},\"temperature\":{:.2},\"unit\":\"{}\",\"humidity\":{}}}",
+            data.time().to_rfc3339(),
+            data.co2(),
+            temperature,
+            options.unit.name(),
+            data.humidity().map_or("null".to_string(), |humidity| format!("{:.2}", humidity)),
+        ),
+    }
+}
+
+fn device_json(device: &DeviceInfo) -> String {
+    let field = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+    format!(
+        "{{\"path\":{},\"serial_number\":{},\"manufacturer\":{},\"product\":{}}}",
+        json_string(&device.path),
+        field(device.serial_number.as_deref()),
+        field(device.manufacturer.as_deref()),
+        field(device.product.as_deref()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hidapi::HidError;
+
+    #[test]
+    fn device_errors_include_their_source() {
+        let error = CliError::Device(Error::Disconnected(HidError::HidApiError { message: "device unplugged".to_string() }));
+        assert_eq!(error.to_string(), "Could not read the device: hidapi error: device unplugged");
+    }
+}
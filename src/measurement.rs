@@ -0,0 +1,218 @@
+//! Single measurements and their aggregation into `DeviceData`.
+//!
+//! The device reports every measurement in its own frame. Each decoded frame is a `Measurement`,
+//! the `Aggregator` combines the latest measurements into a `DeviceData` whenever one of them
+//! changes.
+
+use crate::frame::Frame;
+use crate::item::ItemCode;
+use crate::DeviceData;
+use chrono::{DateTime, Utc};
+
+/// The kinds of measurements reported by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MeasurementKind {
+    /// The filtered CO2 concentration in parts per million (ppm), as shown on the display.
+    Co2,
+    /// The unfiltered CO2 concentration in parts per million (ppm), which reacts faster to changes.
+    RawCo2,
+    /// The ambient temperature in degrees Celsius.
+    Temperature,
+    /// The relative humidity in percent.
+    Humidity,
+}
+
+impl MeasurementKind {
+    /// Returns the kind of measurement reported with the given item code, `None` for other items.
+    pub fn from_item(item: ItemCode) -> Option<Self> {
+        match item {
+            ItemCode::Co2 => Some(MeasurementKind::Co2),
+            ItemCode::RawCo2 => Some(MeasurementKind::RawCo2),
+            ItemCode::Temperature => Some(MeasurementKind::Temperature),
+            ItemCode::Humidity => Some(MeasurementKind::Humidity),
+            _ => None,
+        }
+    }
+
+    /// Returns the item code the device reports this kind of measurement with.
+    pub fn item(self) -> ItemCode {
+        match self {
+            MeasurementKind::Co2 => ItemCode::Co2,
+            MeasurementKind::RawCo2 => ItemCode::RawCo2,
+            MeasurementKind::Temperature => ItemCode::Temperature,
+            MeasurementKind::Humidity => ItemCode::Humidity,
+        }
+    }
+}
+
+/// A single measurement reported by the device.
+///
+/// # Fields
+/// - `kind`: The kind of the measurement.
+/// - `value`: The value of the measurement in the unit of its kind.
+/// - `received_at`: The timestamp when the measurement was read from the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Measurement {
+    pub kind: MeasurementKind,
+    pub value: f32,
+    pub received_at: DateTime<Utc>,
+}
+
+impl Measurement {
+    /// Decodes the measurement carried by a frame, `None` if the frame carries no known measurement.
+    pub fn from_frame(frame: Frame, received_at: DateTime<Utc>) -> Option<Self> {
+        let item = ItemCode::from_code(frame.item);
+        let kind = MeasurementKind::from_item(item)?;
+        let value = item.decode(frame.value)?;
+        Some(Measurement { kind, value, received_at })
+    }
+}
+
+/// Combines single measurements into `DeviceData`.
+///
+/// CO2 and temperature are always required, as every `DeviceData` carries them. Further kinds of
+/// measurements are only waited for if they are listed as required, which allows to use devices
+/// which never report them. Once all required measurements were received, a `DeviceData` is
+/// emitted whenever one of the values changes.
+#[derive(Debug, Clone)]
+pub struct Aggregator {
+    required: Vec<MeasurementKind>,
+    co2: Option<u16>,
+    raw_co2: Option<u16>,
+    temperature: Option<f32>,
+    humidity: Option<f32>,
+    last: Option<DeviceData>,
+}
+
+impl Default for Aggregator {
+    /// Creates an aggregator requiring CO2, temperature and humidity.
+    fn default() -> Self {
+        Self::new(&[MeasurementKind::Co2, MeasurementKind::Temperature, MeasurementKind::Humidity])
+    }
+}
+
+impl Aggregator {
+    /// Creates an aggregator which waits for the given kinds of measurements before emitting.
+    ///
+    /// # Parameters
+    /// - `required`: The kinds of measurements the device reports. CO2 and temperature are always required.
+    pub fn new(required: &[MeasurementKind]) -> Self {
+        Aggregator {
+            required: required.to_vec(),
+            co2: None,
+            raw_co2: None,
+            temperature: None,
+            humidity: None,
+            last: None,
+        }
+    }
+
+    /// Returns the kinds of measurements the aggregator was configured to wait for.
+    pub fn required(&self) -> &[MeasurementKind] {
+        &self.required
+    }
+
+    /// Returns an aggregator with the same requirements, but without any received measurements.
+    pub fn cleared(&self) -> Self {
+        Self::new(&self.required)
+    }
+
+    /// Adds a measurement.
+    ///
+    /// # Returns
+    /// The combined `DeviceData` if all required measurements were received and one of the values
+    /// changed, the timestamp is the one of `measurement`.
+    pub fn push(&mut self, measurement: Measurement) -> Option<DeviceData> {
+        match measurement.kind {
+            MeasurementKind::Co2 => self.co2 = Some(measurement.value as u16),
+            MeasurementKind::RawCo2 => self.raw_co2 = Some(measurement.value as u16),
+            MeasurementKind::Temperature => self.temperature = Some(measurement.value),
+            MeasurementKind::Humidity => self.humidity = Some(measurement.value),
+        }
+        let (Some(co2), Some(temperature)) = (self.co2, self.temperature) else {
+            return None;
+        };
+        if self.required.contains(&MeasurementKind::Humidity) && self.humidity.is_none() {
+            return None;
+        }
+        if self.required.contains(&MeasurementKind::RawCo2) && self.raw_co2.is_none() {
+            return None;
+        }
+        let data = DeviceData::new(measurement.received_at, co2, temperature, self.humidity).with_raw_co2(self.raw_co2);
+        if self.last.is_some_and(|last| last.same_values(&data)) {
+            return None;
+        }
+        self.last = Some(data);
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn measurement(kind: MeasurementKind, value: f32, second: u32) -> Measurement {
+        Measurement { kind, value, received_at: Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, second).unwrap() }
+    }
+
+    #[test]
+    fn decodes_known_frames() {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let co2 = Measurement::from_frame(Frame { item: 0x50, value: 812 }, time).unwrap();
+        assert_eq!((co2.kind, co2.value, co2.received_at), (MeasurementKind::Co2, 812.0, time));
+        let temperature = Measurement::from_frame(Frame { item: 0x42, value: 4711 }, time).unwrap();
+        assert_eq!((temperature.kind, temperature.value), (MeasurementKind::Temperature, 21.29));
+        let humidity = Measurement::from_frame(Frame { item: 0x41, value: 4520 }, time).unwrap();
+        assert_eq!((humidity.kind, humidity.value), (MeasurementKind::Humidity, 45.2));
+        assert_eq!(Measurement::from_frame(Frame { item: 0x6d, value: 1 }, time), None);
+    }
+
+    #[test]
+    fn waits_for_required_measurements() {
+        let mut aggregator = Aggregator::default();
+        assert_eq!(aggregator.push(measurement(MeasurementKind::Co2, 600.0, 0)), None);
+        assert_eq!(aggregator.push(measurement(MeasurementKind::Temperature, 21.5, 1)), None);
+        let data = aggregator.push(measurement(MeasurementKind::Humidity, 40.0, 2)).unwrap();
+        assert_eq!(data, DeviceData::new(measurement(MeasurementKind::Co2, 0.0, 2).received_at, 600, 21.5, Some(40.0)));
+    }
+
+    #[test]
+    fn emits_only_on_change() {
+        let mut aggregator = Aggregator::new(&[MeasurementKind::Co2, MeasurementKind::Temperature]);
+        assert_eq!(aggregator.push(measurement(MeasurementKind::Co2, 600.0, 0)), None);
+        assert!(aggregator.push(measurement(MeasurementKind::Temperature, 21.5, 1)).is_some());
+        assert_eq!(aggregator.push(measurement(MeasurementKind::Temperature, 21.5, 2)), None);
+        assert_eq!(aggregator.push(measurement(MeasurementKind::Co2, 601.0, 3)).map(|data| data.co2()), Some(601));
+    }
+
+    #[test]
+    fn optional_measurements_are_carried_along() {
+        let mut aggregator = Aggregator::new(&[]);
+        aggregator.push(measurement(MeasurementKind::Co2, 600.0, 0));
+        let data = aggregator.push(measurement(MeasurementKind::Temperature, 21.5, 1)).unwrap();
+        assert_eq!((data.humidity(), data.raw_co2()), (None, None));
+        let data = aggregator.push(measurement(MeasurementKind::RawCo2, 620.0, 2)).unwrap();
+        assert_eq!(data.raw_co2(), Some(620));
+    }
+
+    #[test]
+    fn raw_co2_can_be_required() {
+        let mut aggregator = Aggregator::new(&[MeasurementKind::RawCo2]);
+        aggregator.push(measurement(MeasurementKind::Co2, 600.0, 0));
+        assert_eq!(aggregator.push(measurement(MeasurementKind::Temperature, 21.5, 1)), None);
+        assert!(aggregator.push(measurement(MeasurementKind::RawCo2, 620.0, 2)).is_some());
+    }
+
+    #[test]
+    fn cleared_keeps_requirements() {
+        let mut aggregator = Aggregator::new(&[MeasurementKind::Co2, MeasurementKind::Temperature]);
+        aggregator.push(measurement(MeasurementKind::Co2, 600.0, 0));
+        aggregator.push(measurement(MeasurementKind::Temperature, 21.5, 1));
+        let mut cleared = aggregator.cleared();
+        assert_eq!(cleared.required(), aggregator.required());
+        assert_eq!(cleared.push(measurement(MeasurementKind::Co2, 600.0, 2)), None);
+    }
+}
@@ -0,0 +1,725 @@
+//! The monitoring loop reading the device and dispatching the readings.
+//!
+//! All state shared between the `AirControl` handle and its monitoring thread lives in `Monitor`.
+
+use crate::alarm::{Alarm, AlarmCallback, AlarmEvent};
+use crate::capture::CaptureWriter;
+use crate::channel::Subscriber;
+use crate::error::{self, Error, Result};
+use crate::frame::{Frame, FrameDecoder, LinkCounters, FRAME_LENGTH};
+use crate::item::{ItemCode, RawValue};
+use crate::measurement::{Aggregator, Measurement};
+use crate::stats::Statistics;
+use crate::subscription::CallbackList;
+use crate::reconnect::{ConnectionState, ReconnectPolicy};
+use crate::replay::ReplayDevice;
+use crate::transport::Transport;
+use crate::{lock, DeviceData};
+use chrono::{DateTime, Utc};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub(crate) type Callback = Box<dyn FnMut(&DeviceData) + Send>;
+pub(crate) type RawCallback = Box<dyn FnMut(&RawValue) + Send>;
+pub(crate) type MeasurementCallback = Box<dyn FnMut(&Measurement) + Send>;
+pub(crate) type ConnectionCallback = Box<dyn FnMut(ConnectionState) + Send>;
+pub(crate) type Connector = Box<dyn Fn() -> Result<Box<dyn Transport>> + Send + Sync>;
+
+/// The granularity in which sleeping threads check whether the monitoring was stopped.
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+
+/// The state shared between an `AirControl` and its monitoring thread.
+///
+/// # Fields
+/// - `device`: The transport used to talk to the device.
+/// - `connector`: Re-opens the device after a disconnection, `None` if the device cannot be re-opened.
+/// - `callbacks`: A list of callback functions to be called with updated sensor data.
+/// - `subscribers`: The channels fed with updated sensor data.
+/// - `raw_callbacks`: A list of callback functions to be called with the raw value of every frame.
+/// - `raw_subscribers`: The channels fed with the raw value of every frame.
+/// - `measurement_callbacks`: A list of callback functions to be called with every single measurement.
+/// - `measurement_subscribers`: The channels fed with every single measurement.
+/// - `alarm_callbacks`: The alarms with the callback functions to be called with their events.
+/// - `alarm_subscribers`: The alarms with the channels fed with their events.
+/// - `statistics`: The rolling windows fed with updated sensor data.
+/// - `aggregator`: Combines the single measurements into sensor data.
+/// - `connection_callbacks`: A list of callback functions to be called on connection changes.
+/// - `state`: The current state of the connection.
+/// - `policy`: Controls the reconnection after a disconnection.
+/// - `decoder`: The decoder of the reports, which decrypts them if necessary.
+/// - `link`: Counters of the received and rejected reports.
+/// - `poll_interval`: The minimum interval between two sensor data updates.
+/// - `read_timeout`: The time to wait for a single report of the device.
+/// - `capture`: Records every report read from the device, `None` if no capture is running.
+/// - `replay`: The recording delivered instead of the readings of `device`, `None` for a live device.
+/// - `running`: A flag indicating whether the monitoring loop is currently running.
+pub(crate) struct Monitor {
+    pub(crate) device: Mutex<Box<dyn Transport>>,
+    pub(crate) connector: Option<Connector>,
+    pub(crate) callbacks: Arc<CallbackList<Callback>>,
+    pub(crate) subscribers: Mutex<Vec<Subscriber>>,
+    pub(crate) raw_callbacks: Arc<CallbackList<RawCallback>>,
+    pub(crate) raw_subscribers: Mutex<Vec<Sender<RawValue>>>,
+    pub(crate) measurement_callbacks: Arc<CallbackList<MeasurementCallback>>,
+    pub(crate) measurement_subscribers: Mutex<Vec<Sender<Measurement>>>,
+    pub(crate) alarm_callbacks: Arc<CallbackList<(Alarm, AlarmCallback)>>,
+    pub(crate) alarm_subscribers: Mutex<Vec<(Alarm, Sender<AlarmEvent>)>>,
+    pub(crate) statistics: Mutex<Statistics>,
+    pub(crate) aggregator: Mutex<Aggregator>,
+    pub(crate) connection_callbacks: Arc<CallbackList<ConnectionCallback>>,
+    pub(crate) state: Mutex<ConnectionState>,
+    pub(crate) policy: Mutex<ReconnectPolicy>,
+    pub(crate) decoder: FrameDecoder,
+    pub(crate) link: LinkCounters,
+    pub(crate) poll_interval: Mutex<Duration>,
+    pub(crate) read_timeout: Duration,
+    pub(crate) capture: Mutex<Option<CaptureWriter>>,
+    pub(crate) replay: Option<ReplayDevice>,
+    pub(crate) running: AtomicBool,
+}
+
+impl Monitor {
+    pub(crate) fn new(
+        device: Box<dyn Transport>,
+        decoder: FrameDecoder,
+        connector: Option<Connector>,
+        policy: ReconnectPolicy,
+        aggregator: Aggregator,
+        poll_interval: Duration,
+        read_timeout: Duration,
+    ) -> Self {
+        Monitor {
+            device: Mutex::new(device),
+            connector,
+            callbacks: CallbackList::new(),
+            subscribers: Mutex::new(Vec::new()),
+            raw_callbacks: CallbackList::new(),
+            raw_subscribers: Mutex::new(Vec::new()),
+            measurement_callbacks: CallbackList::new(),
+            measurement_subscribers: Mutex::new(Vec::new()),
+            alarm_callbacks: CallbackList::new(),
+            alarm_subscribers: Mutex::new(Vec::new()),
+            statistics: Mutex::new(Statistics::default()),
+            aggregator: Mutex::new(aggregator),
+            connection_callbacks: CallbackList::new(),
+            state: Mutex::new(ConnectionState::Connected),
+            policy: Mutex::new(policy),
+            decoder,
+            link: LinkCounters::default(),
+            poll_interval: Mutex::new(poll_interval),
+            read_timeout,
+            capture: Mutex::new(None),
+            replay: None,
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs the monitoring loop until `running` is cleared or the device is lost for good.
+    ///
+    /// When the loop ends, all subscribed channels are closed.
+    pub(crate) fn run(&self) {
+        self.set_state(ConnectionState::Connected);
+        let mut pending: Option<DeviceData> = None;
+        let mut last_dispatch: Option<Instant> = None;
+        while self.running.load(Ordering::SeqCst) {
+            let result = match &self.replay {
+                Some(replay) => replay.next_reading(self.read_timeout),
+                None => self.read_update(),
+            };
+            match result {
+                Ok(data) => pending = data.or(pending),
+                // Rejected frames are counted in the link statistics and skipped.
+                Err(Error::MalformedFrame(_)) => {}
+                // A device which has nothing to report is still alive, keep waiting for it.
+                Err(Error::ReadTimeout) => {}
+                Err(error) => {
+                    // The end of a replay is expected and not reported.
+                    if self.replay.is_none() {
+                        eprintln!("Error reading data: {}", error::with_sources(&error));
+                    }
+                    if !self.reconnect() {
+                        break;
+                    }
+                }
+            }
+            // Updates arriving faster than the poll interval are coalesced into the latest one. A
+            // replay is paced by its speed instead, so every recorded reading is delivered.
+            let poll_interval = if self.replay.is_some() { Duration::ZERO } else { *lock(&self.poll_interval) };
+            if let Some(data) = pending.filter(|_| last_dispatch.is_none_or(|last| last.elapsed() >= poll_interval)) {
+                self.dispatch(&data);
+                pending = None;
+                last_dispatch = Some(Instant::now());
+            } else if pending.is_none() && self.replay.is_none() {
+                // Readings are only delivered on changes, so a level which holds is reported here.
+                let now = Utc::now();
+                self.dispatch_alarm_events(|alarm| alarm.check(now));
+            }
+        }
+        lock(&self.subscribers).clear();
+        lock(&self.measurement_subscribers).clear();
+        lock(&self.raw_subscribers).clear();
+        lock(&self.alarm_subscribers).clear();
+    }
+
+    /// Reads a single frame from the device and delivers its raw value and measurement.
+    ///
+    /// # Returns
+    /// The sensor data if the aggregator combined it with the measurement of the frame, otherwise `None`.
+    ///
+    /// # Errors
+    /// Returns the errors of `read_frame`.
+    fn read_update(&self) -> Result<Option<DeviceData>> {
+        let (frame, received_at) = {
+            let device = lock(&self.device);
+            self.read_frame(device.as_ref())?
+        };
+        self.dispatch_raw(&RawValue { item: ItemCode::from_code(frame.item), value: frame.value, received_at });
+        let Some(measurement) = Measurement::from_frame(frame, received_at) else {
+            return Ok(None);
+        };
+        self.dispatch_measurement(&measurement);
+        Ok(lock(&self.aggregator).push(measurement))
+    }
+
+    /// Reads a single frame from the device, waiting at most `read_timeout`, but at least one
+    /// millisecond for it.
+    ///
+    /// Reports are captured if a capture is running, decrypted by `decoder` if necessary and
+    /// counted in `link`. The frame is stamped with the current time.
+    ///
+    /// # Errors
+    /// Returns `Error::ReadTimeout` if the device did not send a report within the timeout,
+    /// `Error::MalformedFrame` if the report failed the validation and `Error::Disconnected` if the
+    /// device cannot be read.
+    pub(crate) fn read_frame(&self, device: &dyn Transport) -> Result<(Frame, DateTime<Utc>)> {
+        let mut buf = [0u8; FRAME_LENGTH];
+        let timeout = self.read_timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+        match device.read_timeout(&mut buf, timeout) {
+            Ok(0) => Err(Error::ReadTimeout),
+            Ok(len) => {
+                let received_at = Utc::now();
+                self.capture(received_at, &buf[..len]);
+                let frame = self.link.check(&self.decoder, &buf[..len])?;
+                Ok((frame, received_at))
+            }
+            Err(error) => Err(Error::Disconnected(error)),
+        }
+    }
+
+    /// Reads a single frame from the device and adds its measurement to `aggregator`.
+    ///
+    /// # Returns
+    /// The sensor data if `aggregator` combined it with this frame, otherwise `None`.
+    ///
+    /// # Errors
+    /// Returns the errors of `read_frame`.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn read_data(&self, device: &dyn Transport, aggregator: &mut Aggregator) -> Result<Option<DeviceData>> {
+        let (frame, received_at) = self.read_frame(device)?;
+        Ok(Measurement::from_frame(frame, received_at).and_then(|measurement| aggregator.push(measurement)))
+    }
+
+    /// Appends a report to the running capture. A capture which cannot be written is stopped.
+    fn capture(&self, time: DateTime<Utc>, report: &[u8]) {
+        let mut capture = lock(&self.capture);
+        if let Some(writer) = capture.as_mut() {
+            if let Err(error) = writer.write_report(time, report) {
+                eprintln!("Error capturing report, stopping the capture: {}", error);
+                *capture = None;
+            }
+        }
+    }
+
+    /// Invokes all raw callbacks with `raw` and sends it to all raw channels.
+    fn dispatch_raw(&self, raw: &RawValue) {
+        lock(&self.raw_subscribers).retain(|sender| sender.send(*raw).is_ok());
+
+        self.raw_callbacks.for_each(|cb| {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cb(raw))) {
+                eprintln!("Raw callback panicked: {}", panic_message(payload.as_ref()));
+            }
+        });
+    }
+
+    /// Invokes all measurement callbacks with `measurement` and sends it to all measurement channels.
+    fn dispatch_measurement(&self, measurement: &Measurement) {
+        lock(&self.measurement_subscribers).retain(|sender| sender.send(*measurement).is_ok());
+
+        self.measurement_callbacks.for_each(|cb| {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cb(measurement))) {
+                eprintln!("Measurement callback panicked: {}", panic_message(payload.as_ref()));
+            }
+        });
+    }
+
+    /// Adds `data` to the statistics, invokes all callbacks with it and sends it to all subscribed
+    /// channels. A panicking callback is reported on stderr.
+    fn dispatch(&self, data: &DeviceData) {
+        lock(&self.statistics).push(data);
+        // The channels are sent to without holding the lock, as a blocking channel may wait for a
+        // consumer which subscribes another channel in the meantime.
+        let subscribers = std::mem::take(&mut *lock(&self.subscribers));
+        let subscribers: Vec<Subscriber> = subscribers.into_iter().filter(|subscriber| subscriber.send(*data)).collect();
+        let mut current = lock(&self.subscribers);
+        let added = std::mem::replace(&mut *current, subscribers);
+        current.extend(added);
+        drop(current);
+
+        self.callbacks.for_each(|cb| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| cb(data)));
+            if let Err(payload) = result {
+                eprintln!("Callback panicked: {}", panic_message(payload.as_ref()));
+            }
+        });
+        self.dispatch_alarms(data);
+    }
+
+    /// Updates all alarms with `data` and delivers their events to the alarm callbacks and channels.
+    fn dispatch_alarms(&self, data: &DeviceData) {
+        self.dispatch_alarm_events(|alarm| alarm.update(data));
+    }
+
+    /// Applies `update` to all alarms and delivers the resulting events to the alarm callbacks and channels.
+    fn dispatch_alarm_events(&self, mut update: impl FnMut(&mut Alarm) -> Option<AlarmEvent>) {
+        lock(&self.alarm_subscribers).retain_mut(|(alarm, sender)| match update(alarm) {
+            Some(event) => sender.send(event).is_ok(),
+            None => true,
+        });
+
+        self.alarm_callbacks.for_each(|(alarm, cb)| {
+            let Some(event) = update(alarm) else {
+                return;
+            };
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cb(&event))) {
+                eprintln!("Alarm callback panicked: {}", panic_message(payload.as_ref()));
+            }
+        });
+    }
+
+    /// Re-opens the device according to the reconnect policy.
+    ///
+    /// # Returns
+    /// `true` if the device was re-opened, `false` if all attempts failed or the monitoring was stopped.
+    fn reconnect(&self) -> bool {
+        self.set_state(ConnectionState::Disconnected);
+        let policy = *lock(&self.policy);
+        let Some(connector) = &self.connector else {
+            self.set_state(ConnectionState::GaveUp);
+            return false;
+        };
+        let mut attempt = 1;
+        while policy.allows(attempt) {
+            self.set_state(ConnectionState::Reconnecting { attempt });
+            if !self.sleep(policy.delay(attempt)) {
+                return false;
+            }
+            match connector().and_then(|device| self.initialize(device)) {
+                Ok(()) => {
+                    self.link.reconnected();
+                    self.set_state(ConnectionState::Connected);
+                    return true;
+                }
+                Err(error) => eprintln!("Reconnection attempt {} failed: {}", attempt, error::with_sources(&error)),
+            }
+            attempt += 1;
+        }
+        self.set_state(ConnectionState::GaveUp);
+        false
+    }
+
+    /// Sends the initial feature report to a re-opened device and replaces the current device with it.
+    ///
+    /// The measurements aggregated from the previous device are discarded, so the first reading of
+    /// the re-opened device does not combine values from before and after the disconnect.
+    fn initialize(&self, device: Box<dyn Transport>) -> Result<()> {
+        device.send_feature_report(&self.decoder.feature_report()).map_err(Error::FeatureReport)?;
+        self.decoder.reset();
+        let mut aggregator = lock(&self.aggregator);
+        *aggregator = aggregator.cleared();
+        drop(aggregator);
+        *lock(&self.device) = device;
+        Ok(())
+    }
+
+    /// Updates the connection state and notifies the connection callbacks.
+    fn set_state(&self, state: ConnectionState) {
+        *lock(&self.state) = state;
+        self.connection_callbacks.for_each(|cb| {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cb(state))) {
+                eprintln!("Connection callback panicked: {}", panic_message(payload.as_ref()));
+            }
+        });
+    }
+
+    /// Sleeps for `duration` unless the monitoring is stopped in the meantime.
+    ///
+    /// # Returns
+    /// `true` if the monitoring is still running.
+    fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while self.running.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep(SLEEP_SLICE.min(deadline - now));
+        }
+        false
+    }
+}
+
+/// Extracts the message of a panic payload.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarm::{AirQuality, AlarmConfig};
+    use crate::channel::OverflowPolicy;
+    use crate::frame::{self, FrameError, DEFAULT_KEY};
+    use crate::transport::ScriptedDevice;
+    use std::sync::mpsc;
+
+    const CO2: u8 = 0x50;
+    const TEMPERATURE: u8 = 0x42;
+    const HUMIDITY: u8 = 0x41;
+
+    fn monitor(device: &ScriptedDevice) -> Monitor {
+        Monitor::new(
+            Box::new(device.clone()),
+            FrameDecoder::new(DEFAULT_KEY),
+            None,
+            ReconnectPolicy::disabled(),
+            Aggregator::default(),
+            Duration::ZERO,
+            Duration::from_millis(10),
+        )
+    }
+
+    /// Runs the monitoring loop until the script is exhausted.
+    fn run(monitor: &Monitor) {
+        monitor.running.store(true, Ordering::SeqCst);
+        monitor.run();
+    }
+
+    fn push_reading(device: &ScriptedDevice, co2: u16, temperature: u16, humidity: u16) {
+        device.push_reading(CO2, co2);
+        device.push_reading(TEMPERATURE, temperature);
+        device.push_reading(HUMIDITY, humidity);
+    }
+
+    fn collect(monitor: &Monitor) -> Arc<Mutex<Vec<DeviceData>>> {
+        let readings = Arc::new(Mutex::new(Vec::new()));
+        let sink = readings.clone();
+        monitor.callbacks.add(Box::new(move |data| lock(&sink).push(*data))).detach();
+        readings
+    }
+
+    #[test]
+    fn dispatches_aggregated_readings() {
+        let device = ScriptedDevice::new();
+        push_reading(&device, 650, 4711, 4500);
+        push_reading(&device, 700, 4711, 4500);
+        let monitor = monitor(&device);
+        let readings = collect(&monitor);
+        let channel = {
+            let (sender, receiver) = mpsc::channel();
+            lock(&monitor.subscribers).push(Subscriber::Unbounded(sender));
+            receiver
+        };
+        run(&monitor);
+
+        let readings = lock(&readings);
+        let values: Vec<(u16, f32, Option<f32>)> = readings.iter().map(|data| (data.co2(), data.temperature(), data.humidity())).collect();
+        assert_eq!(values, [(650, 21.29, Some(45.0)), (700, 21.29, Some(45.0))]);
+        assert_eq!(channel.iter().collect::<Vec<_>>(), *readings);
+        assert_eq!(*lock(&monitor.state), ConnectionState::GaveUp);
+    }
+
+    #[test]
+    fn dispatches_raw_values_and_measurements() {
+        let device = ScriptedDevice::new();
+        device.push_reading(0x6d, 0x1234);
+        device.push_reading(CO2, 650);
+        let monitor = monitor(&device);
+        let raw = Arc::new(Mutex::new(Vec::new()));
+        let measurements = Arc::new(Mutex::new(Vec::new()));
+        let (raw_sink, measurement_sink) = (raw.clone(), measurements.clone());
+        monitor.raw_callbacks.add(Box::new(move |value| lock(&raw_sink).push((value.item, value.value)))).detach();
+        monitor.measurement_callbacks.add(Box::new(move |measurement| lock(&measurement_sink).push((measurement.kind, measurement.value)))).detach();
+        run(&monitor);
+
+        assert_eq!(*lock(&raw), [(ItemCode::from_code(0x6d), 0x1234), (ItemCode::Co2, 650)]);
+        assert_eq!(*lock(&measurements), [(crate::MeasurementKind::Co2, 650.0)]);
+    }
+
+    #[test]
+    fn subscribing_while_a_blocking_channel_is_full() {
+        let device = ScriptedDevice::new();
+        let monitor = Arc::new(monitor(&device));
+        let (sender, receiver) = mpsc::sync_channel(1);
+        lock(&monitor.subscribers).push(Subscriber::Bounded(sender, OverflowPolicy::Block));
+        let data = DeviceData::new(Utc::now(), 650, 21.5, Some(45.0));
+
+        let dispatcher = {
+            let monitor = monitor.clone();
+            thread::spawn(move || {
+                monitor.dispatch(&data);
+                // Blocks until the receiver made space.
+                monitor.dispatch(&data);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        let (added_sender, added_receiver) = mpsc::channel();
+        lock(&monitor.subscribers).push(Subscriber::Unbounded(added_sender));
+        assert_eq!(receiver.recv().unwrap(), data);
+        dispatcher.join().unwrap();
+
+        assert_eq!(receiver.recv().unwrap(), data);
+        assert!(added_receiver.try_recv().is_err());
+        assert_eq!(lock(&monitor.subscribers).len(), 2);
+        monitor.dispatch(&data);
+        assert_eq!(added_receiver.recv().unwrap(), data);
+    }
+
+    #[test]
+    fn panicking_callback_does_not_stop_others() {
+        let device = ScriptedDevice::new();
+        push_reading(&device, 650, 4711, 4500);
+        push_reading(&device, 700, 4711, 4500);
+        let monitor = monitor(&device);
+        monitor.callbacks.add(Box::new(|_| panic!("callback failed"))).detach();
+        let readings = collect(&monitor);
+        run(&monitor);
+
+        assert_eq!(lock(&readings).len(), 2);
+    }
+
+    #[test]
+    fn counts_rejected_frames() {
+        let device = ScriptedDevice::new();
+        let mut checksum = Frame { item: CO2, value: 650 }.to_bytes();
+        checksum[3] ^= 0xff;
+        let mut terminator = Frame { item: CO2, value: 650 }.to_bytes();
+        terminator[4] = 0x00;
+        device.push_frame(checksum);
+        device.push_frame(terminator);
+        push_reading(&device, 650, 4711, 4500);
+        let monitor = monitor(&device);
+        let readings = collect(&monitor);
+        run(&monitor);
+
+        assert_eq!(lock(&readings).len(), 1);
+        let stats = monitor.link.snapshot();
+        assert_eq!((stats.frames_received, stats.frames_accepted), (5, 3));
+        assert_eq!((stats.checksum_errors, stats.terminator_errors), (1, 1));
+    }
+
+    #[test]
+    fn reports_malformed_frames() {
+        let device = ScriptedDevice::new();
+        let mut bytes = Frame { item: CO2, value: 650 }.to_bytes();
+        bytes[3] ^= 0xff;
+        device.push_frame(bytes);
+        device.push_reading(CO2, 650);
+        let monitor = monitor(&device);
+
+        let result = monitor.read_frame(&device);
+        assert!(matches!(result, Err(Error::MalformedFrame(FrameError::Checksum { .. }))), "{:?}", result);
+        assert_eq!(monitor.read_frame(&device).map(|(frame, _)| frame).ok(), Some(Frame { item: CO2, value: 650 }));
+        assert!(matches!(monitor.read_frame(&device), Err(Error::Disconnected(_))));
+    }
+
+    #[test]
+    fn decrypts_encrypted_reports() {
+        let device = ScriptedDevice::new();
+        let monitor = monitor(&device);
+        device.send_feature_report(&monitor.decoder.feature_report()).unwrap();
+        device.set_encrypted(true);
+        push_reading(&device, 650, 4711, 4500);
+        let readings = collect(&monitor);
+        run(&monitor);
+
+        assert_eq!(lock(&readings).len(), 1);
+        assert_eq!(monitor.decoder.encoding(), Some(frame::FrameEncoding::Encrypted));
+    }
+
+    #[test]
+    fn coalesces_updates_within_poll_interval() {
+        let device = ScriptedDevice::new();
+        push_reading(&device, 650, 4711, 4500);
+        for co2 in 651..660 {
+            device.push_reading(CO2, co2);
+        }
+        let monitor = monitor(&device);
+        *lock(&monitor.poll_interval) = Duration::from_secs(3600);
+        let readings = collect(&monitor);
+        run(&monitor);
+
+        // The first update is delivered immediately, the later ones wait for the interval.
+        let co2: Vec<u16> = lock(&readings).iter().map(DeviceData::co2).collect();
+        assert_eq!(co2, [650]);
+    }
+
+    #[test]
+    fn reconnects_through_connector() {
+        let first = ScriptedDevice::new();
+        push_reading(&first, 650, 4711, 4500);
+        let second = ScriptedDevice::new();
+        push_reading(&second, 700, 4711, 4500);
+        let attempts = Arc::new(Mutex::new(vec![second.clone()]));
+        let connector: Connector = Box::new(move || {
+            let device = lock(&attempts).pop().ok_or(Error::DeviceNotFound)?;
+            Ok(Box::new(device) as Box<dyn Transport>)
+        });
+        let policy = ReconnectPolicy { initial_delay: Duration::ZERO, max_attempts: Some(1), ..ReconnectPolicy::default() };
+        let monitor = Monitor::new(
+            Box::new(first),
+            FrameDecoder::new(DEFAULT_KEY),
+            Some(connector),
+            policy,
+            Aggregator::default(),
+            Duration::ZERO,
+            Duration::from_millis(10),
+        );
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let sink = states.clone();
+        monitor.connection_callbacks.add(Box::new(move |state| lock(&sink).push(state))).detach();
+        let readings = collect(&monitor);
+        run(&monitor);
+
+        let co2: Vec<u16> = lock(&readings).iter().map(DeviceData::co2).collect();
+        assert_eq!(co2, [650, 700]);
+        assert_eq!(second.feature_reports().len(), 1);
+        assert_eq!(monitor.link.snapshot().reconnects, 1);
+        assert_eq!(
+            *lock(&states),
+            [
+                ConnectionState::Connected,
+                ConnectionState::Disconnected,
+                ConnectionState::Reconnecting { attempt: 1 },
+                ConnectionState::Connected,
+                ConnectionState::Disconnected,
+                ConnectionState::Reconnecting { attempt: 1 },
+                ConnectionState::GaveUp,
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect_discards_measurements_of_the_previous_device() {
+        let first = ScriptedDevice::new();
+        push_reading(&first, 650, 4711, 4500);
+        let second = ScriptedDevice::new();
+        second.push_reading(ItemCode::Co2.code(), 700);
+        let attempts = Arc::new(Mutex::new(vec![second]));
+        let connector: Connector = Box::new(move || {
+            let device = lock(&attempts).pop().ok_or(Error::DeviceNotFound)?;
+            Ok(Box::new(device) as Box<dyn Transport>)
+        });
+        let policy = ReconnectPolicy { initial_delay: Duration::ZERO, max_attempts: Some(1), ..ReconnectPolicy::default() };
+        let monitor = Monitor::new(
+            Box::new(first),
+            FrameDecoder::new(DEFAULT_KEY),
+            Some(connector),
+            policy,
+            Aggregator::default(),
+            Duration::ZERO,
+            Duration::from_millis(10),
+        );
+        let readings = collect(&monitor);
+        run(&monitor);
+
+        let co2: Vec<u16> = lock(&readings).iter().map(DeviceData::co2).collect();
+        assert_eq!(co2, [650]);
+    }
+
+    #[test]
+    fn reports_an_alarm_level_which_holds_without_further_readings() {
+        /// Sends the scripted frames and then keeps timing out, like a device whose values do not change.
+        struct IdleDevice(ScriptedDevice);
+
+        impl Transport for IdleDevice {
+            fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> hidapi::HidResult<usize> {
+                if self.0.pending_frames() == 0 {
+                    thread::sleep(Duration::from_millis(timeout as u64));
+                    return Ok(0);
+                }
+                self.0.read_timeout(buf, timeout)
+            }
+
+            fn send_feature_report(&self, _data: &[u8]) -> hidapi::HidResult<()> {
+                Ok(())
+            }
+        }
+
+        let device = ScriptedDevice::new();
+        push_reading(&device, 1300, 4711, 4520);
+        let monitor = Arc::new(Monitor::new(
+            Box::new(IdleDevice(device)),
+            FrameDecoder::new(DEFAULT_KEY),
+            None,
+            ReconnectPolicy::disabled(),
+            Aggregator::default(),
+            Duration::ZERO,
+            Duration::from_millis(10),
+        ));
+        let (sender, events) = mpsc::channel();
+        let config = AlarmConfig { min_dwell: Duration::from_millis(100), ..AlarmConfig::default() };
+        lock(&monitor.alarm_subscribers).push((Alarm::new(config), sender));
+        monitor.running.store(true, Ordering::SeqCst);
+        let thread = thread::spawn({
+            let monitor = monitor.clone();
+            move || monitor.run()
+        });
+
+        let event = events.recv_timeout(Duration::from_secs(5));
+        monitor.running.store(false, Ordering::SeqCst);
+        thread.join().unwrap();
+
+        let event = event.expect("no alarm was raised");
+        assert_eq!(event.level(), AirQuality::Poor);
+        assert_eq!(event.data().co2(), 1300);
+    }
+
+    #[test]
+    fn read_timeout_is_at_least_one_millisecond() {
+        struct TimeoutDevice(Arc<Mutex<Vec<i32>>>);
+
+        impl Transport for TimeoutDevice {
+            fn read_timeout(&self, _buf: &mut [u8], timeout: i32) -> hidapi::HidResult<usize> {
+                lock(&self.0).push(timeout);
+                Ok(0)
+            }
+
+            fn send_feature_report(&self, _data: &[u8]) -> hidapi::HidResult<()> {
+                Ok(())
+            }
+        }
+
+        let timeouts = Arc::new(Mutex::new(Vec::new()));
+        let device = TimeoutDevice(timeouts.clone());
+        for read_timeout in [Duration::ZERO, Duration::from_micros(500), Duration::from_millis(20)] {
+            let monitor = Monitor::new(
+                Box::new(ScriptedDevice::new()),
+                FrameDecoder::new(DEFAULT_KEY),
+                None,
+                ReconnectPolicy::disabled(),
+                Aggregator::default(),
+                Duration::ZERO,
+                read_timeout,
+            );
+            assert!(matches!(monitor.read_frame(&device), Err(Error::ReadTimeout)));
+        }
+
+        assert_eq!(*lock(&timeouts), [1, 1, 20]);
+    }
+}
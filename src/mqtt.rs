@@ -0,0 +1,569 @@
+//! An MQTT publisher for the readings of devices, enabled by the `mqtt` feature.
+//!
+//! `MqttPublisher` publishes every reading of the attached devices as a JSON object to a state
+//! topic derived from a per-device topic template, e.g. `aircontrol/<serial>/state`. It announces
+//! the sensors to Home Assistant with MQTT discovery config payloads, so they appear without any
+//! configuration, and maintains availability topics:
+//! - The bridge availability topic is `online` while the publisher is connected. The broker sets
+//!   it to `offline` through the last will if the connection is lost.
+//! - The availability topic of every device, e.g. `aircontrol/<serial>/availability`, follows the
+//!   connection state of the device.
+//!
+//! The connection is maintained on a background thread and re-established after errors, the
+//! discovery payloads and availability are published again on every connection.
+//!
+//! ```no_run
+//! # use aircontrol::AirControl;
+//! # use aircontrol::mqtt::{MqttConfig, MqttPublisher};
+//! let mut air_control = AirControl::new().unwrap();
+//! let publisher = MqttPublisher::connect(MqttConfig { host: "broker.local".to_string(), ..MqttConfig::default() });
+//! publisher.attach(&air_control).detach();
+//! air_control.start_monitoring();
+//! ```
+
+use crate::escape::json_string;
+use crate::reconnect::ConnectionState;
+use crate::subscription::Subscription;
+use crate::{lock, AirControl, DeviceData};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The number of messages which may wait for the connection before publishing fails.
+const QUEUE_CAPACITY: usize = 64;
+
+/// The payload of an availability topic while available.
+const ONLINE: &str = "online";
+/// The payload of an availability topic while unavailable.
+const OFFLINE: &str = "offline";
+
+/// The MQTT quality of service levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Qos {
+    /// The message is delivered at most once, it may be lost.
+    AtMostOnce,
+    /// The message is delivered at least once, it may be duplicated.
+    #[default]
+    AtLeastOnce,
+    /// The message is delivered exactly once.
+    ExactlyOnce,
+}
+
+impl From<Qos> for rumqttc::QoS {
+    fn from(qos: Qos) -> Self {
+        match qos {
+            Qos::AtMostOnce => rumqttc::QoS::AtMostOnce,
+            Qos::AtLeastOnce => rumqttc::QoS::AtLeastOnce,
+            Qos::ExactlyOnce => rumqttc::QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// The settings of an `MqttPublisher`.
+///
+/// # Fields
+/// - `host`: The host name or address of the broker.
+/// - `port`: The port of the broker.
+/// - `client_id`: The client identifier, which has to be unique on the broker.
+/// - `credentials`: The user name and password, `None` to connect anonymously.
+/// - `topic`: The template of the base topic of a device, `{serial}` is replaced by its serial number.
+/// - `availability_topic`: The topic of the bridge availability, set to `offline` by the last will.
+/// - `qos`: The quality of service of the published messages.
+/// - `retain`: Whether the broker retains the latest reading of every device.
+/// - `keep_alive`: The interval of the keep alive pings.
+/// - `reconnect_delay`: The time to wait before connecting again after a connection error.
+/// - `discovery_prefix`: The Home Assistant discovery prefix, `None` to publish no discovery payloads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    pub topic: String,
+    pub availability_topic: String,
+    pub qos: Qos,
+    pub retain: bool,
+    pub keep_alive: Duration,
+    pub reconnect_delay: Duration,
+    pub discovery_prefix: Option<String>,
+}
+
+impl Default for MqttConfig {
+    /// Returns the settings for a broker on `localhost:1883` with the topic template
+    /// `aircontrol/{serial}`, retained readings and discovery under `homeassistant`.
+    fn default() -> Self {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: format!("aircontrol-{}", std::process::id()),
+            credentials: None,
+            topic: "aircontrol/{serial}".to_string(),
+            availability_topic: "aircontrol/availability".to_string(),
+            qos: Qos::default(),
+            retain: true,
+            keep_alive: Duration::from_secs(30),
+            reconnect_delay: Duration::from_secs(5),
+            discovery_prefix: Some("homeassistant".to_string()),
+        }
+    }
+}
+
+/// A sensor announced to Home Assistant.
+///
+/// # Fields
+/// - `key`: The key of the value in the state payload, also used in the unique id.
+/// - `name`: The name of the sensor within the device.
+/// - `device_class`: The Home Assistant device class.
+/// - `unit`: The unit of measurement.
+/// - `enabled`: Whether the sensor is enabled by default.
+struct Sensor {
+    key: &'static str,
+    name: &'static str,
+    device_class: &'static str,
+    unit: &'static str,
+    enabled: bool,
+}
+
+const SENSORS: [Sensor; 4] = [
+    Sensor { key: "co2", name: "CO2", device_class: "carbon_dioxide", unit: "ppm", enabled: true },
+    Sensor { key: "raw_co2", name: "Raw CO2", device_class: "carbon_dioxide", unit: "ppm", enabled: false },
+    Sensor { key: "temperature", name: "Temperature", device_class: "temperature", unit: "°C", enabled: true },
+    Sensor { key: "humidity", name: "Humidity", device_class: "humidity", unit: "%", enabled: true },
+];
+
+/// The topics of a single attached device.
+///
+/// # Fields
+/// - `key`: Identifies the device within the publisher.
+/// - `serial`: The serial number of the device.
+/// - `id`: The serial number reduced to characters valid in topics and Home Assistant ids.
+/// - `base`: The base topic of the device, the expanded topic template.
+/// - `available`: Whether the device is connected.
+struct DeviceTopics {
+    key: u64,
+    serial: String,
+    id: String,
+    base: String,
+    available: bool,
+}
+
+impl DeviceTopics {
+    fn state(&self) -> String {
+        format!("{}/state", self.base)
+    }
+
+    fn availability(&self) -> String {
+        format!("{}/availability", self.base)
+    }
+}
+
+/// State shared between the publisher, the callbacks of the devices and the connection thread.
+///
+/// # Fields
+/// - `client`: The handle to queue messages for the broker.
+/// - `config`: The settings of the publisher.
+/// - `devices`: The attached devices.
+/// - `next_key`: The key of the next attached device.
+/// - `connected`: Whether the connection to the broker is established, changed while `devices` is locked.
+struct Shared {
+    client: Client,
+    config: MqttConfig,
+    devices: Mutex<Vec<DeviceTopics>>,
+    next_key: AtomicU64,
+    connected: AtomicBool,
+}
+
+impl Shared {
+    /// Queues a message without blocking, reporting errors on stderr.
+    fn publish(&self, topic: String, qos: Qos, retain: bool, payload: String) {
+        if let Err(error) = self.client.try_publish(topic.as_str(), qos.into(), retain, payload) {
+            eprintln!("Error publishing to {}: {}", topic, error);
+        }
+    }
+
+    /// Publishes the discovery payloads and the availability of a device.
+    fn announce(&self, device: &DeviceTopics) {
+        if let Some(prefix) = &self.config.discovery_prefix {
+            for sensor in &SENSORS {
+                let topic = format!("{}/sensor/aircontrol_{}/{}/config", prefix, device.id, sensor.key);
+                self.publish(topic, Qos::AtLeastOnce, true, self.discovery_payload(device, sensor));
+            }
+        }
+        self.publish_availability(device);
+    }
+
+    fn publish_availability(&self, device: &DeviceTopics) {
+        let payload = if device.available { ONLINE } else { OFFLINE };
+        self.publish(device.availability(), Qos::AtLeastOnce, true, payload.to_string());
+    }
+
+    fn discovery_payload(&self, device: &DeviceTopics, sensor: &Sensor) -> String {
+        format!(
+            concat!(
+                "{{\"name\":{},\"unique_id\":\"aircontrol_{}_{}\",\"object_id\":\"aircontrol_{}_{}\",",
+                "\"state_topic\":{},\"value_template\":\"{{{{ value_json.{} }}}}\",",
+                "\"device_class\":\"{}\",\"unit_of_measurement\":\"{}\",\"state_class\":\"measurement\",",
+                "\"enabled_by_default\":{},",
+                "\"availability\":[{{\"topic\":{}}},{{\"topic\":{}}}],\"availability_mode\":\"all\",",
+                "\"device\":{{\"identifiers\":[\"aircontrol_{}\"],\"name\":{},\"serial_number\":{},",
+                "\"manufacturer\":\"Dostmann\",\"model\":\"TFA AIRCO2NTROL\"}}}}"
+            ),
+            json_string(sensor.name),
+            device.id,
+            sensor.key,
+            device.id,
+            sensor.key,
+            json_string(&device.state()),
+            sensor.key,
+            sensor.device_class,
+            sensor.unit,
+            sensor.enabled,
+            json_string(&self.config.availability_topic),
+            json_string(&device.availability()),
+            device.id,
+            json_string(&format!("AirControl {}", device.serial)),
+            json_string(&device.serial),
+        )
+    }
+}
+
+/// Removes a device from the publisher when the callback owning it is removed, together with the
+/// callback following its connection state, and marks it as offline.
+struct Attachment {
+    shared: Arc<Shared>,
+    key: u64,
+    connection: Option<Subscription>,
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        drop(self.connection.take());
+        let mut devices = lock(&self.shared.devices);
+        let Some(index) = devices.iter().position(|device| device.key == self.key) else {
+            return;
+        };
+        let mut device = devices.remove(index);
+        if self.shared.connected.load(Ordering::SeqCst) && device.available {
+            device.available = false;
+            self.shared.publish_availability(&device);
+        }
+    }
+}
+
+/// Publishes the readings of `AirControl` devices to an MQTT broker.
+///
+/// The connection runs until `disconnect` is called or the process exits, dropping the publisher
+/// does not close it.
+pub struct MqttPublisher {
+    shared: Arc<Shared>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MqttPublisher {
+    /// Connects to the broker on a background thread.
+    ///
+    /// Connection errors are reported on stderr and the connection is retried after
+    /// `config.reconnect_delay`. Messages published meanwhile are queued up to a limit.
+    pub fn connect(config: MqttConfig) -> Self {
+        let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+        options.set_keep_alive(config.keep_alive);
+        options.set_last_will(LastWill::new(config.availability_topic.clone(), OFFLINE, rumqttc::QoS::AtLeastOnce, true));
+        if let Some((user, password)) = &config.credentials {
+            options.set_credentials(user.clone(), password.clone());
+        }
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+        let shared = Arc::new(Shared {
+            client,
+            config,
+            devices: Mutex::new(Vec::new()),
+            next_key: AtomicU64::new(0),
+            connected: AtomicBool::new(false),
+        });
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let shared = shared.clone();
+            let running = running.clone();
+            thread::spawn(move || {
+                for event in connection.iter() {
+                    match event {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            let devices = lock(&shared.devices);
+                            shared.connected.store(true, Ordering::SeqCst);
+                            shared.publish(shared.config.availability_topic.clone(), Qos::AtLeastOnce, true, ONLINE.to_string());
+                            for device in devices.iter() {
+                                shared.announce(device);
+                            }
+                        }
+                        Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                        Ok(_) => {}
+                        Err(_) if !running.load(Ordering::SeqCst) => break,
+                        Err(error) => {
+                            shared.connected.store(false, Ordering::SeqCst);
+                            eprintln!("Error connecting to MQTT broker {}:{}: {}", shared.config.host, shared.config.port, error);
+                            thread::sleep(shared.config.reconnect_delay);
+                        }
+                    }
+                }
+            })
+        };
+        MqttPublisher { shared, running, thread: Some(thread) }
+    }
+
+    /// Publishes the readings of a device, using its serial number in the topics.
+    ///
+    /// Devices without a serial number are named by their path, devices with a custom transport
+    /// `unknown`. Use `attach_with_serial` to choose the name. A name which is already used by
+    /// another attached device gets a suffix, see `attach_with_serial`.
+    ///
+    /// # Returns
+    /// The subscription which stops publishing the readings of the device when it is cancelled or
+    /// dropped, and marks the device as offline. A detached subscription keeps publishing until the
+    /// `AirControl` is dropped.
+    pub fn attach(&self, air_control: &AirControl) -> Subscription {
+        self.attach_with_serial(air_control, &air_control.device_name())
+    }
+
+    /// Publishes the readings of a device, using the given serial number in the topics.
+    ///
+    /// Characters other than ASCII letters, digits, `-` and `_` are replaced by `_` in the topics
+    /// and Home Assistant ids. If another attached device already uses the same id, the id gets
+    /// the suffix `_2`, `_3` and so on, so two devices never publish to the same topics.
+    ///
+    /// # Returns
+    /// The subscription which stops publishing the readings of the device, see `attach`.
+    pub fn attach_with_serial(&self, air_control: &AirControl, serial: &str) -> Subscription {
+        let key = self.shared.next_key.fetch_add(1, Ordering::Relaxed);
+        let state = {
+            let mut devices = lock(&self.shared.devices);
+            let id = unique_topic_id(&devices, topic_id(serial));
+            let device = DeviceTopics {
+                key,
+                serial: serial.to_string(),
+                base: self.shared.config.topic.replace("{serial}", &id),
+                id,
+                available: air_control.connection_state() == ConnectionState::Connected,
+            };
+            let state = device.state();
+            // Devices attached before the connection is established are announced once it is.
+            if self.shared.connected.load(Ordering::SeqCst) {
+                self.shared.announce(&device);
+            }
+            devices.push(device);
+            state
+        };
+
+        let shared = self.shared.clone();
+        let connection = air_control.register_connection_callback(Box::new(move |connection| {
+            let mut devices = lock(&shared.devices);
+            let Some(device) = devices.iter_mut().find(|device| device.key == key) else {
+                return;
+            };
+            let available = connection == ConnectionState::Connected;
+            if device.available != available {
+                device.available = available;
+                shared.publish_availability(device);
+            }
+        }));
+        let attachment = Attachment { shared: self.shared.clone(), key, connection: Some(connection) };
+        air_control.register_callback(Box::new(move |data| {
+            let shared = &attachment.shared;
+            shared.publish(state.clone(), shared.config.qos, shared.config.retain, state_payload(data));
+        }))
+    }
+
+    /// Marks the bridge as offline, closes the connection and waits for the connection thread.
+    ///
+    /// Messages still queued are sent before the connection is closed.
+    pub fn disconnect(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.shared.publish(self.shared.config.availability_topic.clone(), Qos::AtLeastOnce, true, OFFLINE.to_string());
+        if let Err(error) = self.shared.client.disconnect() {
+            eprintln!("Error disconnecting from MQTT broker: {}", error);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns the state payload of a reading, values the reading does not carry are `null`.
+fn state_payload(data: &DeviceData) -> String {
+    format!(
+        "{{\"time\":\"{}\",\"co2\":{},\"raw_co2\":{},\"temperature\":{:.2},\"humidity\":{}}}",
+        data.time().to_rfc3339(),
+        data.co2(),
+        data.raw_co2().map_or("null".to_string(), |raw_co2| raw_co2.to_string()),
+        data.temperature(),
+        data.humidity().map_or("null".to_string(), |humidity| format!("{:.2}", humidity)),
+    )
+}
+
+/// Replaces the characters of a serial number which are not valid in topics and Home Assistant ids.
+fn topic_id(serial: &str) -> String {
+    serial.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+/// Returns `id`, with a numeric suffix if one of `devices` already uses it.
+fn unique_topic_id(devices: &[DeviceTopics], id: String) -> String {
+    let used = |candidate: &str| devices.iter().any(|device| device.id == candidate);
+    if !used(&id) {
+        return id;
+    }
+    (2..).map(|n| format!("{}_{}", id, n)).find(|candidate| !used(candidate)).unwrap_or(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::ItemCode;
+    use crate::transport::ScriptedDevice;
+    use chrono::{TimeZone, Utc};
+    use std::sync::mpsc;
+
+    fn air_control() -> AirControl {
+        let device = ScriptedDevice::new();
+        device.push_reading(ItemCode::Co2.code(), 650);
+        device.push_reading(ItemCode::Temperature.code(), 4711);
+        device.push_reading(ItemCode::Humidity.code(), 4520);
+        AirControl::with_transport(device).unwrap()
+    }
+
+    #[test]
+    fn replaces_invalid_characters_of_topic_ids() {
+        assert_eq!(topic_id("1.40/a b+#"), "1_40_a_b__");
+        assert_eq!(topic_id("Abc-1_2"), "Abc-1_2");
+    }
+
+    #[test]
+    fn devices_with_the_same_name_get_distinct_topics() {
+        let config = MqttConfig { port: 1, reconnect_delay: Duration::from_millis(10), ..MqttConfig::default() };
+        let mut publisher = MqttPublisher::connect(config);
+        let (first, second, third) = (air_control(), air_control(), air_control());
+        let _subscriptions = [publisher.attach(&first), publisher.attach(&second), publisher.attach_with_serial(&third, "unknown")];
+
+        let topics: Vec<(String, String)> = lock(&publisher.shared.devices).iter().map(|device| (device.id.clone(), device.state())).collect();
+        assert_eq!(
+            topics,
+            [
+                ("unknown".to_string(), "aircontrol/unknown/state".to_string()),
+                ("unknown_2".to_string(), "aircontrol/unknown_2/state".to_string()),
+                ("unknown_3".to_string(), "aircontrol/unknown_3/state".to_string()),
+            ]
+        );
+        publisher.disconnect();
+    }
+
+    #[test]
+    fn formats_the_state_payload() {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+        let data = DeviceData::new(time, 650, 21.287, None);
+
+        assert_eq!(
+            state_payload(&data),
+            "{\"time\":\"2024-05-01T12:30:00+00:00\",\"co2\":650,\"raw_co2\":null,\"temperature\":21.29,\"humidity\":null}"
+        );
+        assert!(state_payload(&data.with_raw_co2(Some(700))).contains("\"raw_co2\":700,"));
+    }
+
+    #[test]
+    fn formats_the_discovery_payload() {
+        let (client, _connection) = Client::new(MqttOptions::new("test", "localhost", 1883), 1);
+        let shared = Shared {
+            client,
+            config: MqttConfig::default(),
+            devices: Mutex::new(Vec::new()),
+            next_key: AtomicU64::new(0),
+            connected: AtomicBool::new(false),
+        };
+        let device = DeviceTopics { key: 0, serial: "1.40".to_string(), id: "1_40".to_string(), base: "aircontrol/1_40".to_string(), available: true };
+
+        let payload = shared.discovery_payload(&device, &SENSORS[0]);
+        assert!(payload.starts_with("{\"name\":\"CO2\",\"unique_id\":\"aircontrol_1_40_co2\","), "{}", payload);
+        assert!(payload.contains("\"state_topic\":\"aircontrol/1_40/state\",\"value_template\":\"{{ value_json.co2 }}\","));
+        assert!(payload.contains("\"availability\":[{\"topic\":\"aircontrol/availability\"},{\"topic\":\"aircontrol/1_40/availability\"}]"));
+        assert!(payload.ends_with("\"name\":\"AirControl 1.40\",\"serial_number\":\"1.40\",\"manufacturer\":\"Dostmann\",\"model\":\"TFA AIRCO2NTROL\"}}"));
+    }
+
+    #[test]
+    fn cancelling_the_subscription_removes_the_device() {
+        // Nothing listens on port 1, so the publisher never connects.
+        let config = MqttConfig { port: 1, reconnect_delay: Duration::from_millis(10), ..MqttConfig::default() };
+        let mut publisher = MqttPublisher::connect(config);
+        let air_control = air_control();
+        let subscription = publisher.attach_with_serial(&air_control, "first");
+        publisher.attach(&air_control).detach();
+
+        subscription.cancel();
+        let serials: Vec<String> = lock(&publisher.shared.devices).iter().map(|device| device.serial.clone()).collect();
+        assert_eq!(serials, ["unknown"]);
+        drop(air_control);
+        assert!(lock(&publisher.shared.devices).is_empty());
+        publisher.disconnect();
+    }
+
+    /// Publishes a reading to the broker given by `MQTT_TEST_BROKER`, as `host` or `host:port`.
+    #[test]
+    #[ignore = "requires an MQTT broker, set MQTT_TEST_BROKER to its address"]
+    fn publishes_readings_to_a_broker() {
+        let broker = std::env::var("MQTT_TEST_BROKER").expect("MQTT_TEST_BROKER is not set");
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse().expect("invalid port")),
+            None => (broker, 1883),
+        };
+        let prefix = format!("aircontrol-test-{}", std::process::id());
+        let (subscriber, mut connection) = Client::new(MqttOptions::new(format!("{}-subscriber", prefix), host.clone(), port), 16);
+        subscriber.try_subscribe(format!("{}/#", prefix), rumqttc::QoS::AtLeastOnce).unwrap();
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if sender.send((publish.topic, String::from_utf8_lossy(&publish.payload).to_string())).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let config = MqttConfig {
+            host,
+            port,
+            client_id: format!("{}-publisher", prefix),
+            topic: format!("{}/{{serial}}", prefix),
+            availability_topic: format!("{}/availability", prefix),
+            retain: false,
+            discovery_prefix: None,
+            ..MqttConfig::default()
+        };
+        let mut publisher = MqttPublisher::connect(config);
+        let mut air_control = air_control();
+        let _subscription = publisher.attach_with_serial(&air_control, "1.40");
+        // The retained availability topics are received once both clients are connected.
+        messages.recv_timeout(Duration::from_secs(10)).expect("no message received");
+        air_control.start_monitoring();
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        let state = loop {
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            let (topic, payload) = messages.recv_timeout(timeout).expect("no state received");
+            if topic == format!("{}/1_40/state", prefix) {
+                break payload;
+            }
+        };
+        assert!(state.contains("\"co2\":650,"), "{}", state);
+        publisher.disconnect();
+        // The retained availability topics are cleared again, without waiting for a broker which stopped responding.
+        for topic in ["availability", "1_40/availability"] {
+            let _ = subscriber.try_publish(format!("{}/{}", prefix, topic), rumqttc::QoS::AtLeastOnce, true, Vec::new());
+        }
+        let _ = subscriber.try_disconnect();
+    }
+}
@@ -0,0 +1,68 @@
+//! Reconnection of devices which were disconnected while monitoring.
+//!
+//! When the device cannot be read anymore, the monitoring thread re-opens it according to the
+//! `ReconnectPolicy` of the `AirControl` and reports every change of the connection as a
+//! `ConnectionState` to the registered connection callbacks.
+
+use std::time::Duration;
+
+/// The state of the connection to a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The device is connected and read by the monitoring thread.
+    Connected,
+    /// The device cannot be read anymore.
+    Disconnected,
+    /// The device is about to be re-opened for the given attempt, starting at 1.
+    Reconnecting { attempt: u32 },
+    /// All reconnection attempts failed, the monitoring thread stopped.
+    GaveUp,
+}
+
+/// Controls how often and how fast a disconnected device is re-opened.
+///
+/// The delay before the first attempt is `initial_delay`, every further delay is multiplied by
+/// `multiplier` up to `max_delay`.
+///
+/// # Fields
+/// - `initial_delay`: The delay before the first reconnection attempt.
+/// - `max_delay`: The upper bound of the delay between two attempts.
+/// - `multiplier`: The factor the delay grows with after every failed attempt.
+/// - `max_attempts`: The number of attempts before giving up, `None` to try forever.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_attempts: Some(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns a policy which never reconnects, the monitoring stops at the first disconnection.
+    pub fn disabled() -> Self {
+        ReconnectPolicy { max_attempts: Some(0), ..Self::default() }
+    }
+
+    /// Returns the delay before the given attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let delay = (self.initial_delay.as_secs_f64() * factor).min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(delay)
+    }
+
+    /// Returns whether the given attempt, starting at 1, is allowed by the policy.
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max_attempts| attempt <= max_attempts)
+    }
+}
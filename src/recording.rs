@@ -0,0 +1,545 @@
+//! Recording of sensor data to CSV and JSON Lines files.
+//!
+//! A `RecordingSink` appends every `DeviceData` it is given to a file. It is configured with a
+//! `RecordingSinkBuilder` and attached to an `AirControl` with `AirControl::record`:
+//!
+//! ```no_run
+//! # use aircontrol::AirControl;
+//! # use aircontrol::recording::{RecordingSink, Rotation};
+//! let air_control = AirControl::new().unwrap();
+//! air_control.record(RecordingSink::csv("readings.csv").rotation(Rotation::Daily).open().unwrap()).detach();
+//! ```
+
+use crate::escape::{csv_field, json_string};
+use crate::DeviceData;
+use chrono::format::{Item, StrftimeItems};
+use chrono::NaiveDate;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The file formats a `RecordingSink` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Comma separated values with a header line naming the columns.
+    Csv,
+    /// One JSON object per line, with the columns as keys.
+    JsonLines,
+}
+
+/// The values written for every reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    /// The timestamp of the reading, formatted according to the `TimestampFormat`.
+    Time,
+    /// The filtered CO2 concentration in ppm.
+    Co2,
+    /// The unfiltered CO2 concentration in ppm, empty or `null` if it was not received.
+    RawCo2,
+    /// The temperature in degrees Celsius.
+    Temperature,
+    /// The relative humidity in percent, empty or `null` if the device does not report it.
+    Humidity,
+}
+
+impl Column {
+    /// Returns the name of the column, used in the CSV header and as JSON key.
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Time => "time",
+            Column::Co2 => "co2",
+            Column::RawCo2 => "raw_co2",
+            Column::Temperature => "temperature",
+            Column::Humidity => "humidity",
+        }
+    }
+}
+
+/// The format of the timestamps written for `Column::Time`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// RFC 3339 in UTC, e.g. `2024-05-01T12:30:00.123+00:00`.
+    Rfc3339,
+    /// Seconds since the Unix epoch.
+    UnixSeconds,
+    /// Milliseconds since the Unix epoch.
+    UnixMillis,
+    /// A `chrono` format string applied to the UTC timestamp, e.g. `%Y-%m-%d %H:%M:%S`. The format
+    /// string is validated by `RecordingSinkBuilder::open`.
+    Custom(String),
+}
+
+/// Controls when a `RecordingSink` starts a new file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// All readings are appended to the same file.
+    Never,
+    /// When the readings in the file would grow beyond the given number of bytes, it is renamed
+    /// with the current time appended to its name, e.g. `readings-20240501T123000.csv`, and a new
+    /// file is started. The CSV header is not counted, and a file always holds at least one reading.
+    Size(u64),
+    /// Every UTC day is written to its own file with the date appended to its name, e.g. `readings-2024-05-01.csv`.
+    Daily,
+}
+
+/// Controls how often a `RecordingSink` flushes the file to the disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// The operating system decides when the data reaches the disk.
+    Never,
+    /// The file is synced after every reading, so no reading is lost on a power failure.
+    Always,
+    /// The file is synced after a reading if the given time passed since the last sync.
+    Interval(Duration),
+}
+
+/// Configures a `RecordingSink`, created by `RecordingSink::csv` and `RecordingSink::json_lines`.
+///
+/// # Fields
+/// - `path`: The path of the file. With `Rotation::Daily`, the date is appended to its name.
+/// - `format`: The file format.
+/// - `columns`: The values written for every reading.
+/// - `timestamp_format`: The format of the timestamps.
+/// - `rotation`: Controls when a new file is started.
+/// - `sync`: Controls how often the file is flushed to the disk.
+#[derive(Debug, Clone)]
+pub struct RecordingSinkBuilder {
+    path: PathBuf,
+    format: Format,
+    columns: Vec<Column>,
+    timestamp_format: TimestampFormat,
+    rotation: Rotation,
+    sync: SyncPolicy,
+}
+
+impl RecordingSinkBuilder {
+    fn new(path: PathBuf, format: Format) -> Self {
+        RecordingSinkBuilder {
+            path,
+            format,
+            columns: vec![Column::Time, Column::Co2, Column::Temperature, Column::Humidity],
+            timestamp_format: TimestampFormat::Rfc3339,
+            rotation: Rotation::Never,
+            sync: SyncPolicy::Never,
+        }
+    }
+
+    /// Sets the values written for every reading, by default time, CO2, temperature and humidity.
+    pub fn columns(mut self, columns: &[Column]) -> Self {
+        self.columns = columns.to_vec();
+        self
+    }
+
+    /// Sets the format of the timestamps, by default `TimestampFormat::Rfc3339`.
+    pub fn timestamp_format(mut self, timestamp_format: TimestampFormat) -> Self {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    /// Sets when a new file is started, by default `Rotation::Never`.
+    pub fn rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Sets how often the file is flushed to the disk, by default `SyncPolicy::Never`.
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Creates the sink.
+    ///
+    /// Readings are appended to an existing file. With `Rotation::Daily`, the file is opened with
+    /// the first reading, as its name depends on the date of the reading.
+    ///
+    /// # Errors
+    /// Returns an `io::ErrorKind::InvalidInput` error if a `TimestampFormat::Custom` format string
+    /// is invalid, otherwise the I/O error if the file cannot be opened.
+    pub fn open(self) -> io::Result<RecordingSink> {
+        if let TimestampFormat::Custom(format) = &self.timestamp_format {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid timestamp format `{}`", format)));
+            }
+        }
+        let mut sink = RecordingSink {
+            config: self,
+            file: None,
+            size: 0,
+            header_size: 0,
+            day: None,
+            last_sync: Instant::now(),
+        };
+        if sink.config.rotation != Rotation::Daily {
+            let path = sink.config.path.clone();
+            sink.open_file(&path)?;
+        }
+        Ok(sink)
+    }
+}
+
+/// Appends sensor data to a CSV or JSON Lines file.
+///
+/// # Fields
+/// - `config`: The configuration of the sink.
+/// - `file`: The file currently written, `None` before the first reading with `Rotation::Daily`.
+/// - `size`: The size of the current file in bytes.
+/// - `header_size`: The size of the CSV header at the start of the current file in bytes.
+/// - `day`: The day of the current file with `Rotation::Daily`.
+/// - `last_sync`: The time of the last sync with `SyncPolicy::Interval`.
+#[derive(Debug)]
+pub struct RecordingSink {
+    config: RecordingSinkBuilder,
+    file: Option<File>,
+    size: u64,
+    header_size: u64,
+    day: Option<NaiveDate>,
+    last_sync: Instant,
+}
+
+impl RecordingSink {
+    /// Returns a builder for a sink writing CSV to the file at `path`.
+    pub fn csv(path: impl AsRef<Path>) -> RecordingSinkBuilder {
+        RecordingSinkBuilder::new(path.as_ref().to_path_buf(), Format::Csv)
+    }
+
+    /// Returns a builder for a sink writing JSON Lines to the file at `path`.
+    pub fn json_lines(path: impl AsRef<Path>) -> RecordingSinkBuilder {
+        RecordingSinkBuilder::new(path.as_ref().to_path_buf(), Format::JsonLines)
+    }
+
+    /// Appends a reading to the file, starting a new file first if the rotation requires it.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the file cannot be rotated, written or synced.
+    pub fn write(&mut self, data: &DeviceData) -> io::Result<()> {
+        let record = self.record(data);
+        self.rotate(data, record.len() as u64)?;
+        let Some(file) = self.file.as_mut() else {
+            return Err(io::Error::other("recording file is not open"));
+        };
+        file.write_all(record.as_bytes())?;
+        self.size += record.len() as u64;
+
+        let sync = match self.config.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if sync {
+            file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Flushes the current file to the disk.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the file cannot be synced.
+    pub fn sync(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts a new file if the next record of `len` bytes does not belong into the current one.
+    fn rotate(&mut self, data: &DeviceData, len: u64) -> io::Result<()> {
+        match self.config.rotation {
+            Rotation::Never => Ok(()),
+            Rotation::Size(max_size) => {
+                let records = self.size - self.header_size;
+                if records == 0 || records + len <= max_size {
+                    return Ok(());
+                }
+                self.file = None;
+                let path = self.config.path.clone();
+                let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%S").to_string();
+                fs::rename(&path, unused_path(&path, &stamp))?;
+                self.open_file(&path)
+            }
+            Rotation::Daily => {
+                let day = data.time().date_naive();
+                if self.day == Some(day) {
+                    return Ok(());
+                }
+                let path = suffixed_path(&self.config.path, &day.format("%Y-%m-%d").to_string());
+                self.open_file(&path)?;
+                self.day = Some(day);
+                Ok(())
+            }
+        }
+    }
+
+    /// Opens the file at `path` for appending and writes the CSV header into a new file.
+    ///
+    /// An existing CSV file is assumed to start with the header.
+    fn open_file(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = file.metadata()?.len();
+        self.header_size = 0;
+        if self.config.format == Format::Csv {
+            let names: Vec<&str> = self.config.columns.iter().map(|column| column.name()).collect();
+            let header = format!("{}\n", names.join(","));
+            if self.size == 0 {
+                file.write_all(header.as_bytes())?;
+                self.size = header.len() as u64;
+            }
+            self.header_size = self.size.min(header.len() as u64);
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Formats a reading as a single line of the configured format.
+    fn record(&self, data: &DeviceData) -> String {
+        let values = self.config.columns.iter().map(|&column| (column, self.value(column, data)));
+        match self.config.format {
+            Format::Csv => {
+                let fields: Vec<String> = values.map(|(_, value)| match value {
+                    Value::Text(text) => csv_field(&text),
+                    Value::Number(number) => number,
+                    Value::Missing => String::new(),
+                }).collect();
+                format!("{}\n", fields.join(","))
+            }
+            Format::JsonLines => {
+                let fields: Vec<String> = values.map(|(column, value)| {
+                    let value = match value {
+                        Value::Text(text) => json_string(&text),
+                        Value::Number(number) => number,
+                        Value::Missing => "null".to_string(),
+                    };
+                    format!("\"{}\":{}", column.name(), value)
+                }).collect();
+                format!("{{{}}}\n", fields.join(","))
+            }
+        }
+    }
+
+    fn value(&self, column: Column, data: &DeviceData) -> Value {
+        match column {
+            Column::Time => match &self.config.timestamp_format {
+                TimestampFormat::Rfc3339 => Value::Text(data.time().to_rfc3339()),
+                TimestampFormat::UnixSeconds => Value::Number(data.time().timestamp().to_string()),
+                TimestampFormat::UnixMillis => Value::Number(data.time().timestamp_millis().to_string()),
+                TimestampFormat::Custom(format) => Value::Text(data.time().format(format).to_string()),
+            },
+            Column::Co2 => Value::Number(data.co2().to_string()),
+            Column::RawCo2 => data.raw_co2().map_or(Value::Missing, |raw_co2| Value::Number(raw_co2.to_string())),
+            Column::Temperature => Value::Number(data.temperature().to_string()),
+            Column::Humidity => data.humidity().map_or(Value::Missing, |humidity| Value::Number(humidity.to_string())),
+        }
+    }
+}
+
+/// A single value of a record, quoted according to the format.
+enum Value {
+    Text(String),
+    Number(String),
+    Missing,
+}
+
+/// Returns `path` with `suffix` appended to the file name, before the extension.
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
+
+/// Returns the first path with `suffix` appended to the file name which does not exist yet.
+fn unused_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut candidate = suffixed_path(path, suffix);
+    let mut counter = 1;
+    while candidate.exists() {
+        candidate = suffixed_path(path, &format!("{}-{}", suffix, counter));
+        counter += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    /// Returns a path in the temporary directory which is unique to the test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("aircontrol-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Returns an empty directory in the temporary directory which is unique to the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = temp_path(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        path
+    }
+
+    /// Returns the contents of the files in `dir` sorted by file name and removes the directory.
+    fn take_files(dir: &Path) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (path.file_name().unwrap().to_string_lossy().into_owned(), fs::read_to_string(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        fs::remove_dir_all(dir).unwrap();
+        files
+    }
+
+    const HEADER: &str = "time,co2,temperature,humidity\n";
+    const RECORD: &str = "2024-05-01T12:30:00+00:00,650,21.5,\n";
+
+    fn reading() -> DeviceData {
+        DeviceData::new(Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap(), 650, 21.5, None)
+    }
+
+    #[test]
+    fn writes_csv_with_header_and_quoted_fields() {
+        let path = temp_path("quoted.csv");
+        let mut sink = RecordingSink::csv(&path).timestamp_format(TimestampFormat::Custom("%d.%m.%Y, %H:%M".to_string())).open().unwrap();
+        sink.write(&reading()).unwrap();
+        drop(sink);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content, "time,co2,temperature,humidity\n\"01.05.2024, 12:30\",650,21.5,\n");
+    }
+
+    #[test]
+    fn writes_json_lines_with_null_for_missing_values() {
+        let path = temp_path("missing.jsonl");
+        let mut sink = RecordingSink::json_lines(&path)
+            .columns(&[Column::Time, Column::Co2, Column::RawCo2, Column::Humidity])
+            .timestamp_format(TimestampFormat::UnixSeconds)
+            .open()
+            .unwrap();
+        sink.write(&reading()).unwrap();
+        sink.write(&reading().with_raw_co2(Some(700))).unwrap();
+        drop(sink);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            content,
+            "{\"time\":1714566600,\"co2\":650,\"raw_co2\":null,\"humidity\":null}\n\
+             {\"time\":1714566600,\"co2\":650,\"raw_co2\":700,\"humidity\":null}\n"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_timestamp_formats() {
+        let path = temp_path("invalid.csv");
+        let error = RecordingSink::csv(&path).timestamp_format(TimestampFormat::Custom("%Q".to_string())).open().unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rotates_by_size_without_counting_the_header() {
+        let dir = temp_dir("size");
+        let path = dir.join("readings.csv");
+        let mut sink = RecordingSink::csv(&path).rotation(Rotation::Size(2 * RECORD.len() as u64)).open().unwrap();
+        for _ in 0..5 {
+            sink.write(&reading()).unwrap();
+        }
+        drop(sink);
+
+        let files = take_files(&dir);
+        let contents: Vec<&str> = files.iter().map(|(_, content)| content.as_str()).collect();
+        let full = format!("{}{}{}", HEADER, RECORD, RECORD);
+        let single = format!("{}{}", HEADER, RECORD);
+        assert_eq!(files.len(), 3);
+        assert_eq!(contents.iter().filter(|&&content| content == full).count(), 2);
+        assert_eq!(contents.iter().filter(|&&content| content == single).count(), 1);
+        assert!(files.iter().any(|(name, content)| name == "readings.csv" && *content == single));
+    }
+
+    #[test]
+    fn writes_one_reading_per_file_if_the_size_is_smaller_than_a_record() {
+        let dir = temp_dir("small");
+        let path = dir.join("readings.csv");
+        let mut sink = RecordingSink::csv(&path).rotation(Rotation::Size(1)).open().unwrap();
+        for _ in 0..3 {
+            sink.write(&reading()).unwrap();
+        }
+        drop(sink);
+
+        let files = take_files(&dir);
+        assert_eq!(files.len(), 3);
+        for (_, content) in files {
+            assert_eq!(content, format!("{}{}", HEADER, RECORD));
+        }
+    }
+
+    #[test]
+    fn rotates_daily_at_midnight_utc() {
+        let dir = temp_dir("daily");
+        let path = dir.join("readings.csv");
+        let mut sink = RecordingSink::csv(&path).timestamp_format(TimestampFormat::UnixSeconds).rotation(Rotation::Daily).open().unwrap();
+        assert!(!path.exists());
+        for time in [Utc.with_ymd_and_hms(2024, 5, 1, 23, 59, 59), Utc.with_ymd_and_hms(2024, 5, 2, 0, 0, 0)] {
+            sink.write(&DeviceData::new(time.unwrap(), 650, 21.5, None)).unwrap();
+        }
+        drop(sink);
+
+        assert_eq!(
+            take_files(&dir),
+            vec![
+                ("readings-2024-05-01.csv".to_string(), format!("{}1714607999,650,21.5,\n", HEADER)),
+                ("readings-2024-05-02.csv".to_string(), format!("{}1714608000,650,21.5,\n", HEADER)),
+            ]
+        );
+    }
+
+    #[test]
+    fn appends_to_an_existing_file() {
+        let dir = temp_dir("append");
+        let path = dir.join("readings.csv");
+        for _ in 0..2 {
+            let mut sink = RecordingSink::csv(&path).rotation(Rotation::Size(2 * RECORD.len() as u64)).open().unwrap();
+            sink.write(&reading()).unwrap();
+        }
+        let mut sink = RecordingSink::csv(&path).rotation(Rotation::Size(2 * RECORD.len() as u64)).open().unwrap();
+        sink.write(&reading()).unwrap();
+        drop(sink);
+
+        let files = take_files(&dir);
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().any(|(name, content)| name == "readings.csv" && *content == format!("{}{}", HEADER, RECORD)));
+        assert!(files.iter().any(|(name, content)| name != "readings.csv" && *content == format!("{}{}{}", HEADER, RECORD, RECORD)));
+    }
+
+    #[test]
+    fn syncs_according_to_the_policy() {
+        let dir = temp_dir("sync");
+        for (policy, synced) in [
+            (SyncPolicy::Never, false),
+            (SyncPolicy::Always, true),
+            (SyncPolicy::Interval(Duration::ZERO), true),
+            (SyncPolicy::Interval(Duration::from_secs(3600)), false),
+        ] {
+            let path = dir.join("readings.jsonl");
+            let mut sink = RecordingSink::json_lines(&path).sync(policy).open().unwrap();
+            let opened = sink.last_sync;
+            std::thread::sleep(Duration::from_millis(10));
+            sink.write(&reading()).unwrap();
+            assert_eq!(sink.last_sync > opened, synced, "{:?}", policy);
+            sink.sync().unwrap();
+            drop(sink);
+            fs::remove_file(&path).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
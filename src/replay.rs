@@ -0,0 +1,406 @@
+//! Replay of recorded sensor data as a virtual device.
+//!
+//! `ReplayDevice` delivers recorded `DeviceData` to the callbacks and channels of an `AirControl`
+//! created by `AirControl::replay`, just like the readings of a live device. The files written by
+//! `RecordingSink` are read with `ReplayDevice::open`, as long as they contain the time, CO2 and
+//! temperature columns with RFC 3339 or Unix timestamps.
+
+use crate::error::{self, Error};
+use crate::transport::Transport;
+use crate::{lock, DeviceData};
+use chrono::{DateTime, Utc};
+use hidapi::{HidError, HidResult};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Controls how fast a `ReplayDevice` plays back a recording.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// The readings are delivered with the same gaps between them as they were recorded.
+    Original,
+    /// The gaps between the readings are divided by the given factor, e.g. `60.0` replays an hour
+    /// in a minute. A factor which is not positive replays as fast as possible.
+    Accelerated(f64),
+    /// The readings are delivered as fast as they are read.
+    Unlimited,
+}
+
+/// A virtual device which replays recorded sensor data.
+///
+/// The readings are delivered exactly as they were recorded, with their original timestamps, to
+/// the callbacks and channels of an `AirControl` created by `AirControl::replay`. Once all
+/// readings were delivered, the monitoring ends as if the device had been unplugged.
+///
+/// As the readings are not aggregated from single measurements, the required measurements do not
+/// apply to a replay: recordings without a humidity column replay the same as any other one. No
+/// raw values and single measurements are delivered, and the link statistics stay empty.
+///
+/// The monitoring coalesces updates arriving faster than its poll interval, so fast replays should
+/// be combined with `AirControlBuilder::poll_interval(Duration::ZERO)`:
+///
+/// ```no_run
+/// # use aircontrol::AirControl;
+/// # use aircontrol::replay::{ReplayDevice, ReplaySpeed};
+/// # use std::time::Duration;
+/// let device = ReplayDevice::open("readings.csv", ReplaySpeed::Unlimited).unwrap();
+/// let mut air_control = AirControl::builder().poll_interval(Duration::ZERO).replay(device);
+/// ```
+///
+/// Cloning a `ReplayDevice` yields another handle to the same replay, e.g. to check its progress.
+#[derive(Clone)]
+pub struct ReplayDevice {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+/// # Fields
+/// - `readings`: The readings which were not delivered yet.
+/// - `speed`: The speed of the replay.
+/// - `start`: The time the first reading was delivered and the time it was recorded at.
+struct ReplayState {
+    readings: VecDeque<DeviceData>,
+    speed: ReplaySpeed,
+    start: Option<(Instant, DateTime<Utc>)>,
+}
+
+impl ReplayState {
+    /// Returns the time the reading recorded at `recorded_at` is due, `None` if it is due immediately.
+    fn due(&mut self, recorded_at: DateTime<Utc>) -> Option<Instant> {
+        let factor = match self.speed {
+            ReplaySpeed::Original => 1.0,
+            ReplaySpeed::Accelerated(factor) if factor > 0.0 => factor,
+            ReplaySpeed::Accelerated(_) | ReplaySpeed::Unlimited => return None,
+        };
+        let (started, first) = *self.start.get_or_insert((Instant::now(), recorded_at));
+        // Readings recorded before the first one are due immediately.
+        let offset = (recorded_at - first).to_std().unwrap_or_default();
+        Duration::try_from_secs_f64(offset.as_secs_f64() / factor).ok().and_then(|offset| started.checked_add(offset))
+    }
+}
+
+impl ReplayDevice {
+    /// Creates a device replaying the given readings, which are expected in chronological order.
+    pub fn new(readings: &[DeviceData], speed: ReplaySpeed) -> Self {
+        ReplayDevice {
+            state: Arc::new(Mutex::new(ReplayState { readings: readings.iter().copied().collect(), speed, start: None })),
+        }
+    }
+
+    /// Creates a device replaying a CSV or JSON Lines file written by a `RecordingSink`.
+    ///
+    /// The format is detected from the content of the file.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the file cannot be read and an error of kind `InvalidData` if a
+    /// line cannot be parsed.
+    pub fn open(path: impl AsRef<Path>, speed: ReplaySpeed) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        let readings = parse_recording(&content)
+            .map_err(|(line, message)| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line, message)))?;
+        Ok(Self::new(&readings, speed))
+    }
+
+    /// Returns the number of readings which were not delivered yet.
+    pub fn pending_readings(&self) -> usize {
+        lock(&self.state).readings.len()
+    }
+
+    /// Returns the next reading once it is due, waiting at most `timeout` for it.
+    ///
+    /// # Returns
+    /// The next reading, `None` if it is not due within the timeout.
+    ///
+    /// # Errors
+    /// Returns `Error::Disconnected` once all readings were delivered.
+    pub(crate) fn next_reading(&self, timeout: Duration) -> error::Result<Option<DeviceData>> {
+        let mut state = lock(&self.state);
+        let Some(recorded_at) = state.readings.front().map(DeviceData::time) else {
+            return Err(Error::Disconnected(HidError::HidApiError { message: "replay has no readings left".to_string() }));
+        };
+        if let Some(due) = state.due(recorded_at) {
+            drop(state);
+            let wait = due.saturating_duration_since(Instant::now());
+            if wait > timeout {
+                thread::sleep(timeout);
+                return Ok(None);
+            }
+            thread::sleep(wait);
+            state = lock(&self.state);
+        }
+        Ok(state.readings.pop_front())
+    }
+}
+
+/// The transport of an `AirControl` replaying a recording, which has no device to talk to.
+pub(crate) struct NoDevice;
+
+impl Transport for NoDevice {
+    fn read_timeout(&self, _buf: &mut [u8], _timeout: i32) -> HidResult<usize> {
+        Err(HidError::HidApiError { message: "a replay has no device to read".to_string() })
+    }
+
+    fn send_feature_report(&self, _data: &[u8]) -> HidResult<()> {
+        Ok(())
+    }
+}
+
+/// A single value of a recorded line.
+enum Field {
+    Text(String),
+    Null,
+}
+
+/// Parses the lines of a recording, detecting whether it is CSV or JSON Lines.
+///
+/// # Errors
+/// Returns the line number and a description of the first line which cannot be parsed.
+fn parse_recording(content: &str) -> Result<Vec<DeviceData>, (usize, String)> {
+    let mut lines = content.lines().enumerate().map(|(index, line)| (index + 1, line.trim())).filter(|(_, line)| !line.is_empty());
+    let Some((first_number, first)) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let mut readings = Vec::new();
+    if first.starts_with('{') {
+        for (number, line) in std::iter::once((first_number, first)).chain(lines) {
+            let fields = parse_json_line(line).map_err(|message| (number, message))?;
+            let data = to_device_data(|name| fields.iter().find(|(key, _)| key == name).map(|(_, field)| field));
+            readings.push(data.map_err(|message| (number, message))?);
+        }
+    } else {
+        let header: Vec<String> = parse_csv_line(first);
+        for (number, line) in lines {
+            let values = parse_csv_line(line);
+            let fields: Vec<(&String, Field)> = header.iter().zip(values).map(|(name, value)| {
+                (name, if value.is_empty() { Field::Null } else { Field::Text(value) })
+            }).collect();
+            let data = to_device_data(|name| fields.iter().find(|(key, _)| *key == name).map(|(_, field)| field));
+            readings.push(data.map_err(|message| (number, message))?);
+        }
+    }
+    Ok(readings)
+}
+
+/// Creates a `DeviceData` from the fields of a recorded line, looked up by their column name.
+fn to_device_data<'a>(field: impl Fn(&str) -> Option<&'a Field>) -> Result<DeviceData, String> {
+    let value = |name: &str| match field(name) {
+        Some(Field::Text(text)) => Some(text.as_str()),
+        Some(Field::Null) | None => None,
+    };
+    let number = |name: &str| -> Result<Option<f32>, String> {
+        value(name).map(|text| text.parse::<f32>().map_err(|_| format!("invalid {} `{}`", name, text))).transpose()
+    };
+    let time = value("time").ok_or("missing time")?;
+    let time = parse_timestamp(time).ok_or_else(|| format!("unsupported timestamp `{}`, only RFC 3339 and Unix timestamps can be replayed", time))?;
+    let co2 = number("co2")?.ok_or("missing co2")?;
+    let temperature = number("temperature")?.ok_or("missing temperature")?;
+    let data = DeviceData::new(time, co2 as u16, temperature, number("humidity")?)
+        .with_raw_co2(number("raw_co2")?.map(|raw_co2| raw_co2 as u16));
+    Ok(data)
+}
+
+/// Parses an RFC 3339 timestamp or a Unix timestamp in seconds or milliseconds.
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let timestamp: i64 = value.parse().ok()?;
+    // Milliseconds since the epoch exceed this value since 1973, seconds not before the year 5138.
+    if timestamp.abs() >= 100_000_000_000 {
+        DateTime::from_timestamp_millis(timestamp)
+    } else {
+        DateTime::from_timestamp(timestamp, 0)
+    }
+}
+
+/// Splits a CSV line into its fields, removing the quotes.
+fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Parses a flat JSON object whose values are strings, numbers or `null`.
+fn parse_json_line(line: &str) -> Result<Vec<(String, Field)>, String> {
+    let mut chars = line.chars().peekable();
+    let mut fields = Vec::new();
+    if chars.next() != Some('{') {
+        return Err("expected a JSON object".to_string());
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Ok(fields);
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        if chars.next() != Some('"') {
+            return Err("expected a key".to_string());
+        }
+        let key = parse_json_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next() != Some(':') {
+            return Err(format!("expected `:` after `{}`", key));
+        }
+        skip_whitespace(&mut chars);
+        let field = if chars.next_if_eq(&'"').is_some() {
+            Field::Text(parse_json_string(&mut chars)?)
+        } else {
+            let mut token = String::new();
+            while let Some(c) = chars.next_if(|&c| c != ',' && c != '}' && !c.is_whitespace()) {
+                token.push(c);
+            }
+            match token.as_str() {
+                "null" => Field::Null,
+                "" => return Err(format!("missing value of `{}`", key)),
+                _ => Field::Text(token),
+            }
+        };
+        fields.push((key, field));
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => return Ok(fields),
+            _ => return Err("expected `,` or `}`".to_string()),
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// Parses the rest of a JSON string after its opening quote.
+fn parse_json_string(chars: &mut impl Iterator<Item = char>) -> Result<String, String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some('u') => {
+                    let code: String = chars.take(4).collect();
+                    let c = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32).ok_or("invalid unicode escape")?;
+                    value.push(c);
+                }
+                Some(c) => value.push(c),
+                None => return Err("unterminated string".to_string()),
+            },
+            Some(c) => value.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AirControl;
+    use chrono::TimeZone;
+
+    fn time(seconds: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, seconds).unwrap()
+    }
+
+    #[test]
+    fn replays_the_recorded_readings() {
+        let recorded = [DeviceData::new(time(0), 500, 21.0, Some(40.0)), DeviceData::new(time(5), 600, 22.0, Some(41.0))];
+        let mut air_control = AirControl::replay(ReplayDevice::new(&recorded, ReplaySpeed::Unlimited));
+        let readings = air_control.readings();
+        air_control.start_monitoring();
+
+        assert_eq!(readings.collect::<Vec<_>>(), recorded);
+    }
+
+    #[test]
+    fn replays_every_reading_at_unlimited_speed() {
+        let recorded: Vec<DeviceData> = (0..20).map(|second| DeviceData::new(time(second), 500 + second as u16, 21.0, None)).collect();
+        // The poll interval would coalesce readings arriving this fast from a device.
+        let mut air_control = AirControl::builder().poll_interval(Duration::from_secs(1)).replay(ReplayDevice::new(&recorded, ReplaySpeed::Unlimited));
+        let readings = air_control.readings();
+        air_control.start_monitoring();
+
+        assert_eq!(readings.collect::<Vec<_>>(), recorded);
+    }
+
+    #[test]
+    fn replays_readings_without_humidity() {
+        let recorded = [DeviceData::new(time(0), 500, 21.0, None)];
+        let mut air_control = AirControl::replay(ReplayDevice::new(&recorded, ReplaySpeed::Unlimited));
+        let readings = air_control.readings();
+        air_control.start_monitoring();
+
+        assert_eq!(readings.collect::<Vec<_>>(), recorded);
+    }
+
+    #[test]
+    fn waits_for_readings_which_are_not_due() {
+        let recorded = [DeviceData::new(time(0), 500, 21.0, None), DeviceData::new(time(10), 600, 22.0, None)];
+        let device = ReplayDevice::new(&recorded, ReplaySpeed::Original);
+
+        assert_eq!(device.next_reading(Duration::from_millis(1)).unwrap(), Some(recorded[0]));
+        assert_eq!(device.next_reading(Duration::from_millis(1)).unwrap(), None);
+        assert_eq!(device.pending_readings(), 1);
+    }
+
+    #[test]
+    fn parses_csv_recordings() {
+        let content = "time,co2,raw_co2,temperature,humidity\n\
+                       2024-05-01T12:30:00+00:00,500,,21,40.5\n\
+                       \"1714566605\",600,610,22.25,\n";
+        let readings = parse_recording(content).unwrap();
+
+        assert_eq!(
+            readings,
+            [
+                DeviceData::new(time(0), 500, 21.0, Some(40.5)),
+                DeviceData::new(time(5), 600, 22.25, None).with_raw_co2(Some(610)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_json_lines_recordings() {
+        let content = "{\"time\":1714566600000,\"co2\":500,\"temperature\":21.0,\"humidity\":null}\n\
+                       {\"time\": \"2024-05-01T14:30:05+02:00\", \"co2\": 600, \"temperature\": 22.0}\n";
+        let readings = parse_recording(content).unwrap();
+
+        assert_eq!(readings, [DeviceData::new(time(0), 500, 21.0, None), DeviceData::new(time(5), 600, 22.0, None)]);
+    }
+
+    #[test]
+    fn reports_the_line_which_cannot_be_parsed() {
+        assert_eq!(parse_recording("time,co2\n1714566600,500\n"), Err((2, "missing temperature".to_string())));
+        assert_eq!(parse_recording("time,co2,temperature\n%d.%m,500,21\n").unwrap_err().0, 2);
+        assert!(parse_recording("{\"time\":1714566600,\"co2\":500\n").is_err());
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("2024-05-01T12:30:00Z"), Some(time(0)));
+        assert_eq!(parse_timestamp("1714566600"), Some(time(0)));
+        assert_eq!(parse_timestamp("1714566600000"), Some(time(0)));
+        assert_eq!(parse_timestamp("01.05.2024"), None);
+    }
+}
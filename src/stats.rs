@@ -0,0 +1,379 @@
+//! Rolling statistics over the sensor data updates.
+//!
+//! A `RollingWindow` keeps the values of one kind of measurement which held within a configurable
+//! duration and computes statistics over them. `Statistics` maintains such windows for all kinds
+//! of measurements and is fed by the monitoring thread once a window was added with
+//! `AirControl::track_statistics`.
+//!
+//! The device only reports changed values, so a value holds until the next one was read. All
+//! statistics are weighted by how long every value held within the window, and a stable value
+//! still counts when it was read long before the window started.
+
+use crate::measurement::MeasurementKind;
+use crate::DeviceData;
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The kinds of measurements tracked by `Statistics`.
+const KINDS: [MeasurementKind; 4] = [MeasurementKind::Co2, MeasurementKind::RawCo2, MeasurementKind::Temperature, MeasurementKind::Humidity];
+
+/// A summary of the values within a `RollingWindow`, in the unit of its kind of measurement.
+///
+/// All values except `count`, `min` and `max` are weighted by how long every value held.
+///
+/// # Fields
+/// - `count`: The number of values which held within the window.
+/// - `mean`: The time-weighted mean of the values.
+/// - `min`: The smallest value.
+/// - `max`: The largest value.
+/// - `std_dev`: The time-weighted population standard deviation of the values.
+/// - `median`: The time-weighted 50th percentile of the values.
+/// - `p95`: The time-weighted 95th percentile of the values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WindowStats {
+    pub count: usize,
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+    pub std_dev: f32,
+    pub median: f32,
+    pub p95: f32,
+}
+
+/// The values of one kind of measurement which held within a duration before the end of the window.
+///
+/// A value holds from the time it was read until the next value was read, the latest value holds
+/// until the end of the window. The end is the latest time passed to `advance` or `push`, so the
+/// window keeps moving while the value is stable.
+///
+/// All statistics are weighted by how long every value held within the window. While the values
+/// held for no time at all, e.g. right after the first value was read, they are weighted equally.
+///
+/// # Fields
+/// - `kind`: The kind of measurement.
+/// - `duration`: The length of the window.
+/// - `samples`: The values which held within the window with the time they were read, in
+///   chronological order. The first one may have been read before the window started.
+/// - `end`: The end of the window, `None` before the first value was read.
+#[derive(Debug, Clone)]
+pub struct RollingWindow {
+    kind: MeasurementKind,
+    duration: Duration,
+    samples: VecDeque<(DateTime<Utc>, f32)>,
+    end: Option<DateTime<Utc>>,
+}
+
+impl RollingWindow {
+    /// Creates an empty window.
+    pub fn new(kind: MeasurementKind, duration: Duration) -> Self {
+        RollingWindow { kind, duration, samples: VecDeque::new(), end: None }
+    }
+
+    /// Returns the kind of measurement of the window.
+    pub fn kind(&self) -> MeasurementKind {
+        self.kind
+    }
+
+    /// Returns the length of the window.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Adds a value read at `time` and moves the end of the window to it.
+    ///
+    /// Values older than the latest one are ignored.
+    pub fn push(&mut self, time: DateTime<Utc>, value: f32) {
+        if self.samples.back().is_some_and(|&(last, _)| time < last) {
+            return;
+        }
+        self.samples.push_back((time, value));
+        self.advance(time);
+    }
+
+    /// Moves the end of the window to `now` and removes the values which no longer held within it.
+    ///
+    /// The end never moves backwards, and the window keeps at least the latest value, which still holds.
+    pub fn advance(&mut self, now: DateTime<Utc>) {
+        if self.samples.is_empty() || self.end.is_some_and(|end| now < end) {
+            return;
+        }
+        self.end = Some(now);
+        let start = self.start().unwrap_or(now);
+        while self.samples.get(1).is_some_and(|&(next, _)| next <= start) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns the number of values which held within the window.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Returns whether the window contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Returns the start of the window, `None` while it is empty.
+    pub fn start(&self) -> Option<DateTime<Utc>> {
+        let end = self.end()?;
+        let start = chrono::Duration::from_std(self.duration).ok().and_then(|duration| end.checked_sub_signed(duration));
+        Some(start.unwrap_or(DateTime::<Utc>::MIN_UTC))
+    }
+
+    /// Returns the end of the window, `None` while it is empty.
+    pub fn end(&self) -> Option<DateTime<Utc>> {
+        self.end.filter(|_| !self.is_empty())
+    }
+
+    /// Returns the time-weighted mean of the values, `None` while the window is empty.
+    pub fn mean(&self) -> Option<f32> {
+        let weights = self.weights()?;
+        let total: f64 = weights.iter().map(|&(_, weight)| weight).sum();
+        Some((weights.iter().map(|&(value, weight)| f64::from(value) * weight).sum::<f64>() / total) as f32)
+    }
+
+    /// Returns the smallest value, `None` while the window is empty.
+    pub fn min(&self) -> Option<f32> {
+        self.samples.iter().map(|&(_, value)| value).reduce(f32::min)
+    }
+
+    /// Returns the largest value, `None` while the window is empty.
+    pub fn max(&self) -> Option<f32> {
+        self.samples.iter().map(|&(_, value)| value).reduce(f32::max)
+    }
+
+    /// Returns the time-weighted population standard deviation of the values, `None` while the window is empty.
+    pub fn std_dev(&self) -> Option<f32> {
+        let mean = f64::from(self.mean()?);
+        let weights = self.weights()?;
+        let total: f64 = weights.iter().map(|&(_, weight)| weight).sum();
+        let variance = weights.iter().map(|&(value, weight)| (f64::from(value) - mean).powi(2) * weight).sum::<f64>() / total;
+        Some(variance.sqrt() as f32)
+    }
+
+    /// Returns the given time-weighted percentile of the values.
+    ///
+    /// This is the smallest value which the measurement was at or below for at least the given
+    /// share of the window, e.g. the median is the value it was at or below half of the time.
+    ///
+    /// # Parameters
+    /// - `percentile`: The percentile between 0 and 100, e.g. `50.0` for the median.
+    ///
+    /// # Returns
+    /// The percentile, `None` while the window is empty.
+    pub fn percentile(&self, percentile: f32) -> Option<f32> {
+        let mut weights = self.weights()?;
+        weights.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        let total: f64 = weights.iter().map(|&(_, weight)| weight).sum();
+        let target = f64::from(percentile.clamp(0.0, 100.0)) / 100.0 * total;
+        let mut cumulative = 0.0;
+        for &(value, weight) in &weights {
+            cumulative += weight;
+            if weight > 0.0 && cumulative >= target {
+                return Some(value);
+            }
+        }
+        weights.last().map(|&(value, _)| value)
+    }
+
+    /// Returns how long the value was at or above `threshold` within the window.
+    pub fn time_above(&self, threshold: f32) -> Duration {
+        self.holds().filter(|&(value, _)| value >= threshold).map(|(_, held)| held).sum()
+    }
+
+    /// Returns the summary of the values, `None` while the window is empty.
+    pub fn summary(&self) -> Option<WindowStats> {
+        Some(WindowStats {
+            count: self.len(),
+            mean: self.mean()?,
+            min: self.min()?,
+            max: self.max()?,
+            std_dev: self.std_dev()?,
+            median: self.percentile(50.0)?,
+            p95: self.percentile(95.0)?,
+        })
+    }
+
+    /// Returns every value with how long it held within the window in seconds, or with equal
+    /// weights if the values held for no time at all. `None` while the window is empty.
+    fn weights(&self) -> Option<Vec<(f32, f64)>> {
+        if self.is_empty() {
+            return None;
+        }
+        let weights: Vec<(f32, f64)> = self.holds().map(|(value, held)| (value, held.as_secs_f64())).collect();
+        if weights.iter().all(|&(_, weight)| weight == 0.0) {
+            return Some(weights.into_iter().map(|(value, _)| (value, 1.0)).collect());
+        }
+        Some(weights)
+    }
+
+    /// Returns every value which held within the window together with how long it held there.
+    fn holds(&self) -> impl Iterator<Item = (f32, Duration)> + '_ {
+        let (start, end) = (self.start().unwrap_or_default(), self.end().unwrap_or_default());
+        let next = self.samples.iter().skip(1).map(|&(time, _)| time).chain(std::iter::once(end));
+        self.samples.iter().zip(next).map(move |(&(time, value), next)| (value, (next - time.max(start)).to_std().unwrap_or_default()))
+    }
+}
+
+/// Rolling windows of all kinds of measurements over a set of durations.
+#[derive(Debug, Clone, Default)]
+pub struct Statistics {
+    windows: Vec<RollingWindow>,
+}
+
+impl Statistics {
+    /// Creates statistics over the given window durations.
+    pub fn new(durations: &[Duration]) -> Self {
+        let mut statistics = Self::default();
+        for &duration in durations {
+            statistics.add_window(duration);
+        }
+        statistics
+    }
+
+    /// Adds windows of the given duration for all kinds of measurements, unless they already exist.
+    pub fn add_window(&mut self, duration: Duration) {
+        if self.windows.iter().any(|window| window.duration == duration) {
+            return;
+        }
+        self.windows.extend(KINDS.iter().map(|&kind| RollingWindow::new(kind, duration)));
+    }
+
+    /// Returns the durations of the windows.
+    pub fn durations(&self) -> Vec<Duration> {
+        let mut durations: Vec<Duration> = self.windows.iter().map(|window| window.duration).collect();
+        durations.dedup();
+        durations
+    }
+
+    /// Moves the end of all windows to `now`, see `RollingWindow::advance`.
+    pub fn advance(&mut self, now: DateTime<Utc>) {
+        for window in &mut self.windows {
+            window.advance(now);
+        }
+    }
+
+    /// Adds the values of a reading to all windows. Values the reading does not carry are skipped.
+    pub fn push(&mut self, data: &DeviceData) {
+        for window in &mut self.windows {
+            let value = match window.kind {
+                MeasurementKind::Co2 => Some(f32::from(data.co2())),
+                MeasurementKind::RawCo2 => data.raw_co2().map(f32::from),
+                MeasurementKind::Temperature => Some(data.temperature()),
+                MeasurementKind::Humidity => data.humidity(),
+            };
+            match value {
+                Some(value) => window.push(data.time(), value),
+                None => window.advance(data.time()),
+            }
+        }
+    }
+
+    /// Returns the window of the given kind and duration, `None` if no such window was added.
+    pub fn window(&self, kind: MeasurementKind, duration: Duration) -> Option<&RollingWindow> {
+        self.windows.iter().find(|window| window.kind == kind && window.duration == duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn minutes(minutes: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap() + chrono::Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn weights_values_by_how_long_they_held() {
+        let mut window = RollingWindow::new(MeasurementKind::Co2, HOUR);
+        window.push(minutes(0), 400.0);
+        // Many updates within a single minute do not outweigh the hour the value was stable.
+        for second in 0..60 {
+            window.push(minutes(59) + chrono::Duration::seconds(second), 460.0 + (second % 2) as f32);
+        }
+        window.advance(minutes(60));
+
+        let stats = window.summary().unwrap();
+        assert!((stats.mean - 401.0).abs() < 0.01, "mean {}", stats.mean);
+        assert_eq!(stats.median, 400.0);
+        assert_eq!(stats.p95, 400.0);
+        assert_eq!(stats.p95, window.percentile(95.0).unwrap());
+        assert_eq!(window.percentile(99.0), Some(460.0));
+        assert_eq!((stats.min, stats.max), (400.0, 461.0));
+        assert_eq!(window.time_above(450.0), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_stable_value_holds_until_the_end_of_the_window() {
+        let mut window = RollingWindow::new(MeasurementKind::Co2, HOUR);
+        window.push(minutes(0), 800.0);
+        window.push(minutes(10), 500.0);
+        window.advance(minutes(190));
+
+        assert_eq!(window.len(), 1);
+        assert_eq!(window.start(), Some(minutes(130)));
+        assert_eq!(window.mean(), Some(500.0));
+        assert_eq!(window.std_dev(), Some(0.0));
+        assert_eq!(window.time_above(500.0), HOUR);
+    }
+
+    #[test]
+    fn keeps_the_value_holding_at_the_start() {
+        let mut window = RollingWindow::new(MeasurementKind::Co2, HOUR);
+        window.push(minutes(0), 1000.0);
+        window.push(minutes(30), 1000.0);
+        window.push(minutes(75), 400.0);
+        window.advance(minutes(90));
+
+        // 1000 held from 30 to 75, 400 from 75 to 90.
+        assert_eq!(window.len(), 2);
+        assert_eq!(window.mean(), Some(850.0));
+        assert_eq!(window.min(), Some(400.0));
+        assert_eq!(window.time_above(1000.0), Duration::from_secs(45 * 60));
+    }
+
+    #[test]
+    fn weights_values_equally_without_elapsed_time() {
+        let mut window = RollingWindow::new(MeasurementKind::Temperature, HOUR);
+        assert_eq!(window.summary(), None);
+        window.push(minutes(0), 20.0);
+        window.push(minutes(0), 22.0);
+
+        assert_eq!(window.mean(), Some(21.0));
+        assert_eq!(window.std_dev(), Some(1.0));
+        assert_eq!(window.percentile(50.0), Some(20.0));
+    }
+
+    #[test]
+    fn ignores_values_and_times_from_the_past() {
+        let mut window = RollingWindow::new(MeasurementKind::Co2, HOUR);
+        window.advance(minutes(0));
+        assert_eq!(window.end(), None);
+        window.push(minutes(10), 500.0);
+        window.push(minutes(5), 900.0);
+        window.advance(minutes(20));
+        window.advance(minutes(15));
+
+        assert_eq!(window.len(), 1);
+        assert_eq!(window.end(), Some(minutes(20)));
+    }
+
+    #[test]
+    fn statistics_advance_windows_without_a_value() {
+        let mut statistics = Statistics::new(&[HOUR]);
+        statistics.push(&DeviceData::new(minutes(0), 500, 21.0, Some(40.0)));
+        statistics.push(&DeviceData::new(minutes(120), 600, 21.0, None));
+
+        let humidity = statistics.window(MeasurementKind::Humidity, HOUR).unwrap();
+        assert_eq!(humidity.end(), Some(minutes(120)));
+        assert_eq!(humidity.mean(), Some(40.0));
+        assert!(statistics.window(MeasurementKind::RawCo2, HOUR).unwrap().is_empty());
+        assert_eq!(statistics.durations(), [HOUR]);
+    }
+}
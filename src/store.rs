@@ -0,0 +1,418 @@
+//! Persistent storage of sensor data in SQLite, enabled by the `sqlite` feature.
+//!
+//! A `Store` keeps the readings of any number of devices in a single database file, each under a
+//! device id such as the serial number or the name of the room. Readings are appended with
+//! `AirControl::record_store` and queried back by time range, in downsampled buckets or as the
+//! latest value. The database is opened in WAL mode, so dashboards and the command-line tool can
+//! read it while the readings are written.
+//!
+//! ```no_run
+//! # use aircontrol::AirControl;
+//! # use aircontrol::store::Store;
+//! # use chrono::Utc;
+//! # use std::time::Duration;
+//! let air_control = AirControl::new().unwrap();
+//! air_control.record_store(Store::open("readings.db").unwrap(), "office").detach();
+//!
+//! let store = Store::open("readings.db").unwrap();
+//! let day = Utc::now() - chrono::Duration::days(1);
+//! for bucket in store.buckets("office", day, Utc::now(), Duration::from_secs(3600)).unwrap() {
+//!     println!("{}: {:.0} ppm", bucket.start, bucket.co2.mean);
+//! }
+//! ```
+
+use crate::DeviceData;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// The time to wait for a lock held by another connection, e.g. a writer while reading.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The migrations of the schema. The schema version stored in `PRAGMA user_version` is the number
+/// of migrations applied, so new migrations are only ever appended.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE readings (
+        device TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        co2 INTEGER NOT NULL,
+        raw_co2 INTEGER,
+        temperature REAL NOT NULL,
+        humidity REAL
+    );
+    CREATE INDEX readings_device_time ON readings (device, time);",
+];
+
+/// Errors which can occur while accessing a `Store`.
+#[derive(Debug)]
+pub enum StoreError {
+    /// The database could not be opened, read or written.
+    Sqlite(rusqlite::Error),
+    /// The database was created by a newer version of the crate with a schema this version does not know.
+    UnsupportedSchema(u32),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(error) => write!(f, "Database error: {}", error),
+            StoreError::UnsupportedSchema(version) => write!(f, "Unsupported database schema version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Sqlite(source) => Some(source),
+            StoreError::UnsupportedSchema(_) => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(error: rusqlite::Error) -> Self {
+        StoreError::Sqlite(error)
+    }
+}
+
+/// The mean, minimum and maximum of one kind of measurement within a `Bucket`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BucketStats {
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// The readings of a device within a time interval, returned by `Store::buckets`.
+///
+/// # Fields
+/// - `start`: The start of the interval.
+/// - `count`: The number of readings within the interval, at least 1.
+/// - `co2`: The filtered CO2 concentration in ppm.
+/// - `temperature`: The temperature in degrees Celsius.
+/// - `humidity`: The relative humidity in percent, `None` if no reading carried it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bucket {
+    pub start: DateTime<Utc>,
+    pub count: usize,
+    pub co2: BucketStats,
+    pub temperature: BucketStats,
+    pub humidity: Option<BucketStats>,
+}
+
+/// A SQLite database of sensor data.
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    /// Opens the database at `path`, creating it if necessary, and migrates it to the current schema.
+    ///
+    /// # Errors
+    /// Returns `StoreError::Sqlite` if the database cannot be opened or migrated and
+    /// `StoreError::UnsupportedSchema` if it was created by a newer version of the crate.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        Self::new(connection)
+    }
+
+    /// Opens a database in memory, e.g. for tests. Its content is lost when the store is dropped.
+    ///
+    /// # Errors
+    /// Returns `StoreError::Sqlite` if the database cannot be created.
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut connection: Connection) -> Result<Self, StoreError> {
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version as usize > MIGRATIONS.len() {
+            return Err(StoreError::UnsupportedSchema(version));
+        }
+        let transaction = connection.transaction()?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", index + 1)?;
+        }
+        transaction.commit()?;
+        Ok(Store { connection })
+    }
+
+    /// Returns the version of the schema, the number of migrations applied.
+    ///
+    /// # Errors
+    /// Returns the error of the database if the version cannot be read.
+    pub fn schema_version(&self) -> rusqlite::Result<u32> {
+        self.connection.pragma_query_value(None, "user_version", |row| row.get(0))
+    }
+
+    /// Appends a reading of a device.
+    ///
+    /// # Parameters
+    /// - `device`: The id of the device, e.g. its serial number or the name of the room.
+    /// - `data`: The reading. Its time is stored with millisecond precision.
+    ///
+    /// # Errors
+    /// Returns the error of the database if the reading cannot be written.
+    pub fn insert(&self, device: &str, data: &DeviceData) -> rusqlite::Result<()> {
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO readings (device, time, co2, raw_co2, temperature, humidity) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        statement.execute(params![device, data.time().timestamp_millis(), data.co2(), data.raw_co2(), data.temperature(), data.humidity()])?;
+        Ok(())
+    }
+
+    /// Returns the ids of the devices with readings, in alphabetical order.
+    ///
+    /// # Errors
+    /// Returns the error of the database if the query fails.
+    pub fn devices(&self) -> rusqlite::Result<Vec<String>> {
+        let mut statement = self.connection.prepare_cached("SELECT DISTINCT device FROM readings ORDER BY device")?;
+        let devices = statement.query_map([], |row| row.get(0))?;
+        devices.collect()
+    }
+
+    /// Returns the latest reading of a device, `None` if there is none.
+    ///
+    /// # Errors
+    /// Returns the error of the database if the query fails.
+    pub fn latest(&self, device: &str) -> rusqlite::Result<Option<DeviceData>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT time, co2, raw_co2, temperature, humidity FROM readings WHERE device = ?1 ORDER BY time DESC LIMIT 1",
+        )?;
+        statement.query_row([device], reading).optional()
+    }
+
+    /// Returns the readings of a device within a time range, in chronological order.
+    ///
+    /// # Parameters
+    /// - `device`: The id of the device.
+    /// - `from`: The start of the range, inclusive.
+    /// - `to`: The end of the range, exclusive.
+    ///
+    /// # Errors
+    /// Returns the error of the database if the query fails.
+    pub fn range(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> rusqlite::Result<Vec<DeviceData>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT time, co2, raw_co2, temperature, humidity FROM readings
+             WHERE device = ?1 AND time >= ?2 AND time < ?3 ORDER BY time",
+        )?;
+        let readings = statement.query_map(params![device, from.timestamp_millis(), to.timestamp_millis()], reading)?;
+        readings.collect()
+    }
+
+    /// Returns the readings of a device within a time range, downsampled into buckets of equal width.
+    ///
+    /// # Parameters
+    /// - `device`: The id of the device.
+    /// - `from`: The start of the range, inclusive.
+    /// - `to`: The end of the range, exclusive.
+    /// - `width`: The width of the buckets, at least one millisecond.
+    ///
+    /// # Returns
+    /// The buckets in chronological order. They are aligned to multiples of `width` since the Unix
+    /// epoch, so hourly buckets start at full hours, and the first and last bucket only cover the
+    /// part within the range. Buckets without readings are left out.
+    ///
+    /// # Errors
+    /// Returns the error of the database if the query fails.
+    pub fn buckets(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>, width: Duration) -> rusqlite::Result<Vec<Bucket>> {
+        let width = i64::try_from(width.as_millis()).unwrap_or(i64::MAX).max(1);
+        // SQLite divides toward zero, so the remainder is made non-negative to round times before
+        // the epoch down as well, like `div_euclid`.
+        let mut statement = self.connection.prepare_cached(
+            "SELECT (time - ((time % ?4) + ?4) % ?4) / ?4 AS bucket, COUNT(*),
+                    AVG(co2), MIN(co2), MAX(co2),
+                    AVG(temperature), MIN(temperature), MAX(temperature),
+                    AVG(humidity), MIN(humidity), MAX(humidity)
+             FROM readings WHERE device = ?1 AND time >= ?2 AND time < ?3
+             GROUP BY bucket ORDER BY bucket",
+        )?;
+        let buckets = statement.query_map(params![device, from.timestamp_millis(), to.timestamp_millis(), width], |row| {
+            let bucket: i64 = row.get(0)?;
+            let stats = |mean, min, max| -> rusqlite::Result<Option<BucketStats>> {
+                let (mean, min, max): (Option<f64>, Option<f64>, Option<f64>) = (row.get(mean)?, row.get(min)?, row.get(max)?);
+                Ok(mean.zip(min).zip(max).map(|((mean, min), max)| BucketStats { mean: mean as f32, min: min as f32, max: max as f32 }))
+            };
+            let no_value = |column| rusqlite::Error::InvalidColumnType(column, "NULL".to_string(), rusqlite::types::Type::Null);
+            Ok(Bucket {
+                start: timestamp(bucket.saturating_mul(width)),
+                count: row.get(1)?,
+                co2: stats(2, 3, 4)?.ok_or_else(|| no_value(2))?,
+                temperature: stats(5, 6, 7)?.ok_or_else(|| no_value(5))?,
+                humidity: stats(8, 9, 10)?,
+            })
+        })?;
+        buckets.collect()
+    }
+
+    /// Deletes the readings of all devices older than `before`, e.g. to keep only the last months.
+    ///
+    /// # Returns
+    /// The number of deleted readings.
+    ///
+    /// # Errors
+    /// Returns the error of the database if the readings cannot be deleted.
+    pub fn prune(&self, before: DateTime<Utc>) -> rusqlite::Result<usize> {
+        self.connection.execute("DELETE FROM readings WHERE time < ?1", [before.timestamp_millis()])
+    }
+}
+
+/// Reads a `DeviceData` from a row with the columns time, co2, raw_co2, temperature and humidity.
+fn reading(row: &Row<'_>) -> rusqlite::Result<DeviceData> {
+    let data = DeviceData::new(timestamp(row.get(0)?), row.get(1)?, row.get(3)?, row.get(4)?);
+    Ok(data.with_raw_co2(row.get(2)?))
+}
+
+fn timestamp(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    fn store(readings: &[(&str, DeviceData)]) -> Store {
+        let store = Store::open_in_memory().unwrap();
+        for (device, data) in readings {
+            store.insert(device, data).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn migrates_a_new_database_to_the_current_schema() {
+        let store = Store::open_in_memory().unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn reopening_keeps_the_readings_and_the_schema() {
+        let path = std::env::temp_dir().join(format!("aircontrol-{}-reopen.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let data = DeviceData::new(time(12, 0), 650, 21.5, Some(40.0));
+        Store::open(&path).unwrap().insert("office", &data).unwrap();
+
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len() as u32);
+        assert_eq!(store.latest("office").unwrap(), Some(data));
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_a_database_with_a_newer_schema() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        match Store::new(connection) {
+            Err(StoreError::UnsupportedSchema(version)) => assert_eq!(version as usize, MIGRATIONS.len() + 1),
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("a newer schema was accepted"),
+        }
+    }
+
+    #[test]
+    fn applies_only_the_missing_migrations() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(MIGRATIONS[0]).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        // Running the first migration again would fail because the table exists.
+        let store = Store::new(connection).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn round_trips_readings_with_millisecond_precision() {
+        let at = time(12, 0) + chrono::Duration::microseconds(123_456);
+        let store = store(&[("office", DeviceData::new(at, 812, 21.25, None).with_raw_co2(Some(830)))]);
+        let data = store.latest("office").unwrap().unwrap();
+        assert_eq!(data.time(), time(12, 0) + chrono::Duration::milliseconds(123));
+        assert_eq!((data.co2(), data.raw_co2(), data.temperature(), data.humidity()), (812, Some(830), 21.25, None));
+    }
+
+    #[test]
+    fn queries_readings_by_device_and_range() {
+        let store = store(&[
+            ("office", DeviceData::new(time(12, 0), 600, 21.0, None)),
+            ("office", DeviceData::new(time(12, 30), 700, 22.0, None)),
+            ("office", DeviceData::new(time(13, 0), 800, 23.0, None)),
+            ("kitchen", DeviceData::new(time(12, 30), 900, 24.0, None)),
+        ]);
+        assert_eq!(store.devices().unwrap(), ["kitchen", "office"]);
+        let co2: Vec<u16> = store.range("office", time(12, 0), time(13, 0)).unwrap().iter().map(DeviceData::co2).collect();
+        assert_eq!(co2, [600, 700]);
+        assert_eq!(store.latest("office").unwrap().map(|data| data.co2()), Some(800));
+        assert_eq!(store.latest("bedroom").unwrap(), None);
+    }
+
+    #[test]
+    fn downsamples_into_aligned_buckets() {
+        let store = store(&[
+            ("office", DeviceData::new(time(11, 50), 500, 20.0, None)),
+            ("office", DeviceData::new(time(12, 10), 600, 21.0, Some(40.0))),
+            ("office", DeviceData::new(time(12, 40), 800, 22.0, None)),
+            ("office", DeviceData::new(time(14, 20), 1000, 23.0, Some(50.0))),
+            ("kitchen", DeviceData::new(time(12, 20), 2000, 30.0, None)),
+        ]);
+        let buckets = store.buckets("office", time(11, 55), time(15, 0), Duration::from_secs(3600)).unwrap();
+        // The reading before the range and the empty hour from 13:00 are left out.
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].start, time(12, 0));
+        assert_eq!(buckets[0].count, 2);
+        assert_eq!(buckets[0].co2, BucketStats { mean: 700.0, min: 600.0, max: 800.0 });
+        assert_eq!(buckets[0].temperature, BucketStats { mean: 21.5, min: 21.0, max: 22.0 });
+        assert_eq!(buckets[0].humidity, Some(BucketStats { mean: 40.0, min: 40.0, max: 40.0 }));
+        assert_eq!(buckets[1].start, time(14, 0));
+        assert_eq!(buckets[1].count, 1);
+
+        let buckets = store.buckets("office", time(11, 0), time(12, 0), Duration::from_secs(3600)).unwrap();
+        assert_eq!(buckets[0].humidity, None);
+    }
+
+    #[test]
+    fn aligns_buckets_before_the_epoch() {
+        let epoch = DateTime::<Utc>::UNIX_EPOCH;
+        let minutes = |minutes| epoch + chrono::Duration::minutes(minutes);
+        let store = store(&[
+            ("office", DeviceData::new(minutes(-90), 500, 20.0, None)),
+            ("office", DeviceData::new(minutes(-30), 600, 21.0, None)),
+            ("office", DeviceData::new(minutes(-10), 800, 22.0, None)),
+            ("office", DeviceData::new(minutes(10), 1000, 23.0, None)),
+        ]);
+        let buckets = store.buckets("office", minutes(-75), minutes(60), Duration::from_secs(3600)).unwrap();
+        let starts: Vec<(DateTime<Utc>, usize)> = buckets.iter().map(|bucket| (bucket.start, bucket.count)).collect();
+        assert_eq!(starts, [(minutes(-60), 2), (epoch, 1)]);
+        assert_eq!(buckets[0].co2.mean, 700.0);
+    }
+
+    #[test]
+    fn takes_bucket_widths_below_a_millisecond_as_one_millisecond() {
+        let store = store(&[("office", DeviceData::new(time(12, 0), 600, 21.0, None))]);
+        let buckets = store.buckets("office", time(11, 0), time(13, 0), Duration::from_micros(10)).unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].start, time(12, 0));
+    }
+
+    #[test]
+    fn prunes_old_readings_of_all_devices() {
+        let store = store(&[
+            ("office", DeviceData::new(time(11, 0), 600, 21.0, None)),
+            ("office", DeviceData::new(time(13, 0), 700, 22.0, None)),
+            ("kitchen", DeviceData::new(time(11, 30), 800, 23.0, None)),
+        ]);
+        assert_eq!(store.prune(time(12, 0)).unwrap(), 2);
+        assert_eq!(store.devices().unwrap(), ["office"]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Handles to remove registered callbacks.
+//!
+//! Every `register_*` method of `AirControl` returns a `Subscription`. The callback stays
+//! registered as long as the subscription is alive, and is removed when it is dropped or
+//! cancelled with `Subscription::cancel`. `Subscription::detach` keeps the callback registered for
+//! the lifetime of the `AirControl` instead:
+//!
+//! ```no_run
+//! # use aircontrol::AirControl;
+//! let air_control = AirControl::new().unwrap();
+//! let subscription = air_control.register_callback(Box::new(|data| println!("{} ppm", data.co2())));
+//! // ...
+//! subscription.cancel();
+//! ```
+//!
+//! Callbacks are kept in a `CallbackList`, which the monitoring thread reads from a snapshot, so
+//! registering and removing callbacks never waits for the callbacks currently being invoked. A
+//! callback may even cancel its own subscription or register further callbacks.
+
+use crate::lock;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// A registered callback.
+///
+/// # Fields
+/// - `id`: Identifies the callback within its list.
+/// - `cancelled`: Set when the callback was removed, so snapshots taken before do not invoke it anymore.
+/// - `callback`: The callback, locked while it is invoked.
+struct Entry<T> {
+    id: u64,
+    cancelled: AtomicBool,
+    callback: Mutex<T>,
+}
+
+/// A list of callbacks which can be changed while the monitoring thread invokes them.
+///
+/// # Fields
+/// - `entries`: The registered callbacks. Changes replace the list if the monitoring thread holds a snapshot of it.
+/// - `next_id`: The id of the next registered callback.
+pub(crate) struct CallbackList<T> {
+    entries: Mutex<Arc<Vec<Arc<Entry<T>>>>>,
+    next_id: AtomicU64,
+}
+
+impl<T: Send + 'static> CallbackList<T> {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(CallbackList { entries: Mutex::new(Arc::new(Vec::new())), next_id: AtomicU64::new(0) })
+    }
+
+    /// Registers a callback and returns the subscription which removes it again.
+    pub(crate) fn add(self: &Arc<Self>, callback: T) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry { id, cancelled: AtomicBool::new(false), callback: Mutex::new(callback) });
+        Arc::make_mut(&mut lock(&self.entries)).push(entry);
+        let list: Weak<dyn Unsubscribe> = Arc::downgrade(self) as Weak<dyn Unsubscribe>;
+        Subscription { list: Some(list), id }
+    }
+
+    /// Calls `f` with every callback which is registered when the call starts and not removed
+    /// before its turn. The list is not locked while `f` runs.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&mut T)) {
+        let entries = lock(&self.entries).clone();
+        for entry in entries.iter() {
+            if entry.cancelled.load(Ordering::SeqCst) {
+                continue;
+            }
+            let mut callback = lock(&entry.callback);
+            // The callback may have been removed while waiting for another invocation to finish.
+            if entry.cancelled.load(Ordering::SeqCst) {
+                continue;
+            }
+            f(&mut callback);
+        }
+    }
+}
+
+/// Removes callbacks from a `CallbackList` without knowing the type of its callbacks.
+trait Unsubscribe: Send + Sync {
+    fn remove(&self, id: u64);
+}
+
+impl<T: Send> Unsubscribe for CallbackList<T> {
+    fn remove(&self, id: u64) {
+        let removed = {
+            let mut entries = lock(&self.entries);
+            let Some(index) = entries.iter().position(|entry| entry.id == id) else {
+                return;
+            };
+            Arc::make_mut(&mut entries).remove(index)
+        };
+        removed.cancelled.store(true, Ordering::SeqCst);
+        // The callback is dropped outside the lock, as dropping it may cancel further subscriptions.
+        drop(removed);
+    }
+}
+
+/// Keeps a callback registered, returned by the `register_*` methods of `AirControl`.
+///
+/// The callback is removed when the subscription is dropped or cancelled. It is not invoked once
+/// the removal returned, except by an invocation which already started on the monitoring thread.
+#[must_use = "the callback is removed when the subscription is dropped, use `detach` to keep it"]
+pub struct Subscription {
+    list: Option<Weak<dyn Unsubscribe>>,
+    id: u64,
+}
+
+impl Subscription {
+    /// Removes the callback.
+    pub fn cancel(self) {}
+
+    /// Keeps the callback registered as long as the `AirControl` exists.
+    pub fn detach(mut self) {
+        self.list = None;
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(list) = self.list.take().and_then(|list| list.upgrade()) {
+            list.remove(self.id);
+        }
+    }
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription").field("id", &self.id).field("detached", &self.list.is_none()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    type Callback = Box<dyn FnMut() + Send>;
+
+    #[test]
+    fn cancelled_callbacks_are_not_invoked() {
+        let list = CallbackList::<Callback>::new();
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = calls.clone();
+        let subscription = list.add(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        list.for_each(|callback| callback());
+        subscription.cancel();
+        list.for_each(|callback| callback());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn detached_callbacks_stay_registered() {
+        let list = CallbackList::<Callback>::new();
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = calls.clone();
+        list.add(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }))
+        .detach();
+        list.for_each(|callback| callback());
+        list.for_each(|callback| callback());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn callbacks_waiting_for_their_lock_are_not_invoked_after_cancelling() {
+        let list = CallbackList::<Callback>::new();
+        let calls = Arc::new(AtomicU64::new(0));
+        let (entered, entered_receiver) = mpsc::channel();
+        let (gate, gate_receiver) = mpsc::channel::<()>();
+        let counter = calls.clone();
+        let subscription = list.add(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let _ = entered.send(());
+            let _ = gate_receiver.recv();
+        }));
+
+        // The first invocation holds the lock of the callback until the gate opens.
+        let first = thread::spawn({
+            let list = list.clone();
+            move || list.for_each(|callback| callback())
+        });
+        entered_receiver.recv().unwrap();
+        // The second invocation passed the first check and waits for the lock.
+        let second = thread::spawn({
+            let list = list.clone();
+            move || list.for_each(|callback| callback())
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        subscription.cancel();
+        drop(gate);
+        first.join().unwrap();
+        second.join().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,113 @@
+//! Abstraction over the HID transport used to talk to an AirControl device.
+//!
+//! `AirControl` only needs two operations from the underlying device: reading 8 byte reports with
+//! a timeout and sending a feature report. Both are captured by the `Transport` trait. The `hidapi`
+//! device is the default implementation, `ScriptedDevice` is an in-memory device which replays
+//! predefined frames, so the crate can be used and tested without any hardware attached.
+
+use crate::frame::{self, Frame, FRAME_LENGTH};
+use crate::lock;
+use hidapi::{HidDevice, HidError, HidResult};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// The operations `AirControl` needs from a device.
+///
+/// The signatures mirror the ones of `hidapi::HidDevice`, so the hidapi device can be used directly.
+pub trait Transport: Send {
+    /// Reads a single input report into `buf`, waiting at most `timeout` milliseconds.
+    ///
+    /// # Returns
+    /// The number of bytes actually read, `0` if the timeout elapsed without any data.
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> HidResult<usize>;
+
+    /// Sends a feature report to the device. The first byte is the report id.
+    fn send_feature_report(&self, data: &[u8]) -> HidResult<()>;
+}
+
+impl Transport for HidDevice {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> HidResult<usize> {
+        HidDevice::read_timeout(self, buf, timeout)
+    }
+
+    fn send_feature_report(&self, data: &[u8]) -> HidResult<()> {
+        HidDevice::send_feature_report(self, data)
+    }
+}
+
+/// An in-memory device that plays back a script of frames.
+///
+/// Cloning a `ScriptedDevice` yields another handle to the same script, so frames can be pushed and
+/// the sent feature reports can be inspected after the device was handed over to `AirControl`.
+/// Once all frames were read, every further read fails as if the device had been unplugged.
+/// An encrypting device can be simulated with `set_encrypted`, in which case every frame is
+/// encrypted with the key of the last feature report before it is returned.
+#[derive(Clone, Default)]
+pub struct ScriptedDevice {
+    state: Arc<Mutex<ScriptState>>,
+}
+
+#[derive(Default)]
+struct ScriptState {
+    frames: VecDeque<[u8; 8]>,
+    feature_reports: Vec<Vec<u8>>,
+    encrypted: bool,
+}
+
+impl ScriptedDevice {
+    /// Creates a scripted device without any frames.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a raw 8 byte report to the script.
+    pub fn push_frame(&self, frame: [u8; 8]) {
+        lock(&self.state).frames.push_back(frame);
+    }
+
+    /// Appends a well-formed frame for the item code `item` carrying `value` to the script.
+    ///
+    /// The frame contains the checksum over the first three bytes and the `0x0D` terminator, just
+    /// like the frames sent by the real device.
+    pub fn push_reading(&self, item: u8, value: u16) {
+        self.push_frame(Frame { item, value }.to_bytes());
+    }
+
+    /// Enables or disables the encryption of the frames, like it is done by older device firmware.
+    pub fn set_encrypted(&self, encrypted: bool) {
+        lock(&self.state).encrypted = encrypted;
+    }
+
+    /// Returns the number of frames which were not read yet.
+    pub fn pending_frames(&self) -> usize {
+        lock(&self.state).frames.len()
+    }
+
+    /// Returns all feature reports sent to the device so far, in the order they were sent.
+    pub fn feature_reports(&self) -> Vec<Vec<u8>> {
+        lock(&self.state).feature_reports.clone()
+    }
+}
+
+impl Transport for ScriptedDevice {
+    fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> HidResult<usize> {
+        let mut state = lock(&self.state);
+        let mut frame = state.frames.pop_front().ok_or_else(|| HidError::HidApiError {
+            message: "scripted device has no frames left".to_string(),
+        })?;
+        if state.encrypted {
+            let key = state.feature_reports.last()
+                .and_then(|report| <[u8; FRAME_LENGTH]>::try_from(report.get(1..)?).ok())
+                .unwrap_or_default();
+            frame = frame::encrypt(&key, &frame);
+        }
+        let len = buf.len().min(frame.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+
+    fn send_feature_report(&self, data: &[u8]) -> HidResult<()> {
+        lock(&self.state).feature_reports.push(data.to_vec());
+        Ok(())
+    }
+}